@@ -0,0 +1,596 @@
+//! One-pass adaptive Huffman coding with Vitter's algorithm.
+//!
+//! Encoder and decoder start from the same empty tree and update it after
+//! every symbol, so no code table is transmitted and the data can be coded as
+//! it arrives. A symbol seen for the first time is sent as the code of the
+//! not-yet-transmitted (NYT) leaf followed by its 9-bit value. The stream ends
+//! with a dedicated end symbol, so its length does not have to be known up
+//! front.
+
+use std::io::{self, Read, Write};
+
+use crate::{codec::Codec, error::malformed};
+
+// Byte values plus the end of stream symbol
+const SYMBOLS: usize = 257;
+const END: u16 = 256;
+const NYT: u16 = 257;
+
+// Leaves for every symbol and the NYT leaf, joined into a binary tree
+const SLOTS: usize = 2 * (SYMBOLS + 1) - 1;
+const ROOT: usize = SLOTS - 1;
+const NONE: usize = usize::MAX;
+
+// Bytes collected before they are passed to the underlying writer
+const BUFFER_SIZE: usize = 4096;
+
+#[derive(Clone, Copy)]
+enum Content {
+    Leaf(u16),
+    Internal { left: usize, right: usize },
+}
+
+#[derive(Clone, Copy)]
+struct Node {
+    weight: u64,
+    content: Content,
+}
+
+impl Node {
+    /// Nodes are numbered in order of this key: by weight, and leaves before
+    /// internal nodes of the same weight.
+    fn key(&self) -> (u64, bool) {
+        (
+            self.weight,
+            matches!(self.content, Content::Internal { .. }),
+        )
+    }
+}
+
+/// The code tree, stored by node number. A slot keeps its place in the tree;
+/// moving a node means moving its contents to another slot.
+struct Tree {
+    nodes: Vec<Node>,
+    parent: Vec<usize>,
+    leaf_of: [usize; SYMBOLS],
+    nyt: usize,
+}
+
+impl Tree {
+    fn new() -> Self {
+        let nodes = vec![
+            Node {
+                weight: 0,
+                content: Content::Leaf(NYT),
+            };
+            SLOTS
+        ];
+
+        Tree {
+            nodes,
+            parent: vec![NONE; SLOTS],
+            leaf_of: [NONE; SYMBOLS],
+            nyt: ROOT,
+        }
+    }
+
+    /// Appends the code of `symbol`, or the escape for a new symbol, to
+    /// `bits`, root first.
+    fn encode(&self, symbol: u16, bits: &mut Vec<bool>) {
+        let leaf = self.leaf_of[symbol as usize];
+        let start = bits.len();
+
+        if leaf == NONE {
+            // Raw value, least significant bit first as the code is reversed
+            bits.extend((0..9).map(|i| symbol & (1 << i) != 0));
+        }
+
+        let mut slot = if leaf == NONE { self.nyt } else { leaf };
+
+        while slot != ROOT {
+            let parent = self.parent[slot];
+            let Content::Internal { right, .. } = self.nodes[parent].content else {
+                unreachable!()
+            };
+
+            bits.push(slot == right);
+            slot = parent;
+        }
+
+        bits[start..].reverse();
+    }
+
+    fn decode<R: Read>(&self, reader: &mut BitReader<R>) -> io::Result<u16> {
+        let mut slot = ROOT;
+
+        while let Content::Internal { left, right } = self.nodes[slot].content {
+            slot = if reader.read_bit()? { right } else { left };
+        }
+
+        let Content::Leaf(symbol) = self.nodes[slot].content else {
+            unreachable!()
+        };
+
+        if symbol != NYT {
+            return Ok(symbol);
+        }
+
+        let mut symbol = 0;
+
+        for _ in 0..9 {
+            symbol = (symbol << 1) | reader.read_bit()? as u16;
+        }
+
+        if symbol as usize >= SYMBOLS || self.leaf_of[symbol as usize] != NONE {
+            return Err(malformed("Invalid new symbol"));
+        }
+
+        Ok(symbol)
+    }
+
+    fn update(&mut self, symbol: u16) {
+        let mut leaf_to_increment = NONE;
+        let mut node = self.leaf_of[symbol as usize];
+
+        if node == NONE {
+            // Split the NYT leaf into a new NYT leaf and a leaf for the symbol
+            let old = self.nyt;
+            let (nyt, leaf) = (old - 2, old - 1);
+
+            self.nodes[nyt].content = Content::Leaf(NYT);
+            self.nodes[leaf].content = Content::Leaf(symbol);
+            self.nodes[old].content = Content::Internal {
+                left: nyt,
+                right: leaf,
+            };
+
+            self.parent[nyt] = old;
+            self.parent[leaf] = old;
+            self.leaf_of[symbol as usize] = leaf;
+            self.nyt = nyt;
+
+            leaf_to_increment = leaf;
+            node = old;
+        } else {
+            node = self.swap_with_leader(node);
+
+            // The parent has the same weight as a leaf next to the NYT leaf, so
+            // it has to be incremented first
+            if self.parent[node] == self.parent[self.nyt] {
+                leaf_to_increment = node;
+                node = self.parent[node];
+            }
+        }
+
+        while node != NONE {
+            node = self.slide_and_increment(node);
+        }
+
+        if leaf_to_increment != NONE {
+            self.slide_and_increment(leaf_to_increment);
+        }
+    }
+
+    /// Moves the node to the highest numbered slot with the same key, and
+    /// returns that slot.
+    fn swap_with_leader(&mut self, slot: usize) -> usize {
+        let leader = self.last_slot_before(slot, self.nodes[slot].key(), true);
+
+        if leader != slot {
+            self.nodes.swap(slot, leader);
+            self.relink(slot);
+            self.relink(leader);
+        }
+
+        leader
+    }
+
+    /// Increments the weight of a node, sliding it past the nodes that now
+    /// have to come before it, and returns the node whose weight is next.
+    fn slide_and_increment(&mut self, slot: usize) -> usize {
+        let slot = self.swap_with_leader(slot);
+        let (weight, internal) = self.nodes[slot].key();
+        let former_parent = self.parent[slot];
+
+        // A leaf passes the internal nodes of its old weight, an internal node
+        // the leaves of its new weight
+        let target = self.last_slot_before(slot, (weight + 1, internal), false);
+
+        self.nodes[slot..=target].rotate_left(1);
+        for moved in slot..=target {
+            self.relink(moved);
+        }
+
+        self.nodes[target].weight += 1;
+
+        if internal {
+            former_parent
+        } else {
+            self.parent[target]
+        }
+    }
+
+    /// Returns the last slot from `slot` on whose key is below `key`, or equal
+    /// to it if `inclusive`. Blocks of equal weight are short, so this scans.
+    fn last_slot_before(&self, slot: usize, key: (u64, bool), inclusive: bool) -> usize {
+        let mut last = slot;
+
+        while last < ROOT {
+            let next = self.nodes[last + 1].key();
+
+            if next > key || (next == key && !inclusive) {
+                break;
+            }
+
+            last += 1;
+        }
+
+        last
+    }
+
+    /// Points the links into the node now stored in `slot` back at it.
+    fn relink(&mut self, slot: usize) {
+        match self.nodes[slot].content {
+            Content::Leaf(NYT) => self.nyt = slot,
+            Content::Leaf(symbol) => self.leaf_of[symbol as usize] = slot,
+            Content::Internal { left, right } => {
+                self.parent[left] = slot;
+                self.parent[right] = slot;
+            }
+        }
+    }
+}
+
+/// One tree, or one per previous byte.
+struct Model {
+    trees: Vec<Option<Box<Tree>>>,
+    context: usize,
+}
+
+impl Model {
+    fn new(order1: bool) -> Self {
+        Model {
+            trees: (0..if order1 { 256 } else { 1 }).map(|_| None).collect(),
+            context: 0,
+        }
+    }
+
+    fn tree(&mut self) -> &mut Tree {
+        self.trees[self.context].get_or_insert_with(|| Box::new(Tree::new()))
+    }
+
+    fn update(&mut self, symbol: u16) {
+        self.tree().update(symbol);
+
+        if self.trees.len() > 1 && symbol != END {
+            self.context = symbol as usize;
+        }
+    }
+}
+
+/// Compresses everything written to it into `W` as it arrives.
+///
+/// [`Write::flush`] passes on every complete byte; up to seven bits stay
+/// behind until more data is written or the stream is finished with
+/// [`Encoder::finish`].
+pub struct Encoder<W: Write> {
+    writer: W,
+    model: Model,
+    bits: Vec<bool>,
+    buffer: Vec<u8>,
+    byte: u8,
+    filled: u32,
+}
+
+impl<W: Write> Encoder<W> {
+    /// With `order1`, every byte is coded with a tree of its own for the byte
+    /// before it.
+    pub fn new(writer: W, order1: bool) -> Self {
+        Encoder {
+            writer,
+            model: Model::new(order1),
+            bits: Vec::new(),
+            buffer: Vec::new(),
+            byte: 0,
+            filled: 0,
+        }
+    }
+
+    /// Writes the end of the stream and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.put(END);
+
+        if self.filled > 0 {
+            self.buffer.push(self.byte << (8 - self.filled));
+        }
+
+        self.writer.write_all(&self.buffer)?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    fn put(&mut self, symbol: u16) {
+        self.bits.clear();
+        self.model.tree().encode(symbol, &mut self.bits);
+        self.model.update(symbol);
+
+        for &bit in &self.bits {
+            self.byte = (self.byte << 1) | bit as u8;
+            self.filled += 1;
+
+            if self.filled == 8 {
+                self.buffer.push(self.byte);
+                self.filled = 0;
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.put(byte as u16);
+        }
+
+        if self.buffer.len() >= BUFFER_SIZE {
+            self.writer.write_all(&self.buffer)?;
+            self.buffer.clear();
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.write_all(&self.buffer)?;
+        self.buffer.clear();
+        self.writer.flush()
+    }
+}
+
+struct BitReader<R: Read> {
+    reader: R,
+    byte: u8,
+    remaining: u32,
+}
+
+impl<R: Read> BitReader<R> {
+    fn read_bit(&mut self) -> io::Result<bool> {
+        if self.remaining == 0 {
+            let mut byte = [0u8; 1];
+            self.reader.read_exact(&mut byte)?;
+            self.byte = byte[0];
+            self.remaining = 8;
+        }
+
+        self.remaining -= 1;
+        Ok(self.byte & (1 << self.remaining) != 0)
+    }
+}
+
+/// Decompresses a stream read from `R`, decoding as little input as each
+/// read needs. Reading bit by bit, it should be given a buffered reader.
+pub struct Decoder<R: Read> {
+    reader: BitReader<R>,
+    model: Model,
+    finished: bool,
+}
+
+impl<R: Read> Decoder<R> {
+    /// `order1` has to match the encoder.
+    pub fn new(reader: R, order1: bool) -> Self {
+        Decoder {
+            reader: BitReader {
+                reader,
+                byte: 0,
+                remaining: 0,
+            },
+            model: Model::new(order1),
+            finished: false,
+        }
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut len = 0;
+
+        while len < buf.len() && !self.finished {
+            let symbol = self.model.tree().decode(&mut self.reader)?;
+            self.model.update(symbol);
+
+            if symbol == END {
+                self.finished = true;
+            } else {
+                buf[len] = symbol as u8;
+                len += 1;
+            }
+        }
+
+        Ok(len)
+    }
+}
+
+/// Adaptive Huffman coder; the payload is the bare [`Encoder`] stream.
+pub struct AdaptiveHuffmanCoder {
+    order1: bool,
+}
+
+impl AdaptiveHuffmanCoder {
+    pub fn new() -> Self {
+        AdaptiveHuffmanCoder { order1: false }
+    }
+
+    /// Uses a tree per previous byte, registered as `adaptive-huffman-o1`.
+    pub fn with_order1(mut self, order1: bool) -> Self {
+        self.order1 = order1;
+        self
+    }
+}
+
+impl Default for AdaptiveHuffmanCoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Codec for AdaptiveHuffmanCoder {
+    fn name(&self) -> &'static str {
+        if self.order1 {
+            "adaptive-huffman-o1"
+        } else {
+            "adaptive-huffman"
+        }
+    }
+
+    fn id(&self) -> u8 {
+        if self.order1 { 10 } else { 9 }
+    }
+
+    fn encode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = Encoder::new(Vec::new(), self.order1);
+        encoder.write_all(bytes)?;
+        encoder.finish()
+    }
+
+    fn decode(&self, bytes: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+
+        // One byte more than allowed tells a stream that goes on from one
+        // that ends right at the limit
+        Decoder::new(bytes, self.order1)
+            .take(max_len as u64 + 1)
+            .read_to_end(&mut output)?;
+
+        if output.len() > max_len {
+            return Err(malformed("stream longer than the block"));
+        }
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks the invariant of Vitter's algorithm: by slot, weights never
+    /// decrease and leaves come before internal nodes of the same weight.
+    /// Also checks that internal nodes weigh as much as their children and
+    /// that all links agree.
+    fn assert_invariant(tree: &Tree) {
+        for slot in tree.nyt..ROOT {
+            assert!(tree.nodes[slot].key() <= tree.nodes[slot + 1].key());
+        }
+
+        for slot in tree.nyt..=ROOT {
+            match tree.nodes[slot].content {
+                Content::Internal { left, right } => {
+                    assert_eq!((tree.parent[left], tree.parent[right]), (slot, slot));
+                    assert_eq!(
+                        tree.nodes[slot].weight,
+                        tree.nodes[left].weight + tree.nodes[right].weight
+                    );
+                }
+                Content::Leaf(NYT) => {
+                    assert_eq!(tree.nyt, slot);
+                    assert_eq!(tree.nodes[slot].weight, 0);
+                }
+                Content::Leaf(symbol) => assert_eq!(tree.leaf_of[symbol as usize], slot),
+            }
+        }
+    }
+
+    fn code_length(tree: &Tree, symbol: u16) -> usize {
+        let mut bits = Vec::new();
+        tree.encode(symbol, &mut bits);
+        bits.len()
+    }
+
+    #[test]
+    fn updates_keep_the_invariant() {
+        let mut tree = Tree::new();
+        let mut state = 1u64;
+
+        for _ in 0..5000 {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+
+            // Skewed towards small symbols, so that weights keep passing
+            // each other
+            let symbol = ((state >> 33) % 257) as u16 >> ((state >> 20) % 7);
+            tree.update(symbol);
+            assert_invariant(&tree);
+        }
+    }
+
+    #[test]
+    fn frequent_symbols_get_short_codes() {
+        let mut tree = Tree::new();
+
+        for (symbol, count) in [(b'a', 100), (b'b', 20), (b'c', 5), (b'd', 1)] {
+            for _ in 0..count {
+                tree.update(symbol as u16);
+            }
+        }
+
+        let lengths: Vec<usize> = [b'a', b'b', b'c', b'd']
+            .iter()
+            .map(|&symbol| code_length(&tree, symbol as u16))
+            .collect();
+
+        assert_eq!(lengths[0], 1);
+        assert!(
+            lengths.windows(2).all(|pair| pair[0] <= pair[1]),
+            "{lengths:?}"
+        );
+
+        // A new symbol costs the NYT code and its 9-bit value
+        assert!(code_length(&tree, b'e' as u16) > 9);
+    }
+
+    #[test]
+    fn streams_do_not_depend_on_write_or_read_sizes() {
+        let input: Vec<u8> = (0..=255u8).chain(b"abracadabra".repeat(500)).collect();
+
+        for order1 in [false, true] {
+            let mut encoder = Encoder::new(Vec::new(), order1);
+            encoder.write_all(&input).unwrap();
+            let expected = encoder.finish().unwrap();
+
+            let mut encoder = Encoder::new(Vec::new(), order1);
+            let mut rest = &input[..];
+            for size in 1.. {
+                if rest.is_empty() {
+                    break;
+                }
+                let (part, next) = rest.split_at(size.min(rest.len()));
+                encoder.write_all(part).unwrap();
+                encoder.flush().unwrap();
+                rest = next;
+            }
+            assert!(encoder.finish().unwrap() == expected);
+
+            let mut decoder = Decoder::new(&expected[..], order1);
+            let mut output = Vec::new();
+            let mut byte = [0u8; 1];
+            while decoder.read(&mut byte).unwrap() == 1 {
+                output.push(byte[0]);
+            }
+            assert!(output == input);
+        }
+    }
+
+    #[test]
+    fn empty_input_is_only_the_end_symbol() {
+        for order1 in [false, true] {
+            let coder = AdaptiveHuffmanCoder::new().with_order1(order1);
+            let encoded = coder.encode(&[]).unwrap();
+
+            // The NYT code is empty in a new tree, leaving the 9-bit value
+            assert_eq!(encoded.len(), 2);
+            assert!(coder.decode(&encoded, 0).unwrap().is_empty());
+        }
+    }
+}
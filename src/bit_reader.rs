@@ -0,0 +1,87 @@
+//! MSB-first bit reader over a byte slice that can look ahead without
+//! consuming, as needed by table-driven Huffman decoding. Reads the same bit
+//! order as `bitbit::BitWriter` writes.
+
+use std::io;
+
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    // Buffered bits, aligned to the most significant end
+    buffer: u64,
+    buffered: u32,
+    // Bits of input not consumed yet, not counting the zeros peeked past the end
+    remaining: u64,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        BitReader {
+            bytes,
+            position: 0,
+            buffer: 0,
+            buffered: 0,
+            remaining: bytes.len() as u64 * 8,
+        }
+    }
+
+    /// Returns the next `count` bits, between 1 and 32, without consuming
+    /// them. Past the end of the input the bits read as zeros.
+    pub fn peek(&mut self, count: u32) -> u32 {
+        debug_assert!((1..=32).contains(&count));
+
+        if self.buffered < count {
+            self.refill();
+        }
+
+        (self.buffer >> (64 - count)) as u32
+    }
+
+    /// Consumes `count` bits that have been peeked.
+    pub fn consume(&mut self, count: u32) -> io::Result<()> {
+        if count as u64 > self.remaining {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        self.buffer <<= count;
+        self.buffered -= count;
+        self.remaining -= count as u64;
+
+        Ok(())
+    }
+
+    pub fn read_bit(&mut self) -> io::Result<bool> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    /// Reads `count` bits, at most 32, most significant first.
+    pub fn read_bits(&mut self, count: u32) -> io::Result<u32> {
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let bits = self.peek(count);
+        self.consume(count)?;
+
+        Ok(bits)
+    }
+
+    /// Skips the bits up to the next byte boundary.
+    pub fn align_to_byte(&mut self) -> io::Result<()> {
+        self.consume((self.remaining % 8) as u32)
+    }
+
+    /// Returns whether all input has been consumed.
+    pub fn is_empty(&self) -> bool {
+        self.remaining == 0
+    }
+
+    fn refill(&mut self) {
+        while self.buffered <= 56 {
+            let byte = self.bytes.get(self.position).copied().unwrap_or(0);
+            self.buffer |= (byte as u64) << (56 - self.buffered);
+            self.buffered += 8;
+            self.position += 1;
+        }
+    }
+}
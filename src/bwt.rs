@@ -1,84 +1,271 @@
 pub fn bwt(bytes: &[u8]) -> (Vec<u8>, usize) {
+    let (last_column, rows) = bwt_sampled(bytes, 1);
+    (last_column, rows[0])
+}
+
+/// Like [`bwt`], but returns the rows of the rotations starting at `samples`
+/// evenly spaced positions, the first of them being the index of the input.
+pub fn bwt_sampled(bytes: &[u8], samples: usize) -> (Vec<u8>, Vec<usize>) {
     if bytes.is_empty() {
-        return (Vec::new(), 0);
+        return (Vec::new(), vec![0; samples]);
     }
 
     let n = bytes.len();
+    let step = n.div_ceil(samples);
 
-    // Create indices for rotations instead of storing full rotations
-    let mut indices: Vec<usize> = (0..n).collect();
-
-    // Sort indices based on the rotations they represent
-    indices.sort_by(|&a, &b| {
-        // Compare rotation starting at position a with rotation starting at position b
-        for i in 0..n {
-            let char_a = bytes[(a + i) % n];
-            let char_b = bytes[(b + i) % n];
-            match char_a.cmp(&char_b) {
-                std::cmp::Ordering::Equal => continue,
-                other => return other,
-            }
-        }
-        std::cmp::Ordering::Equal
-    });
+    // Starting from its least rotation, the input is a Lyndon word or a power
+    // of one, whose suffixes sort like its rotations (equal rotations aside,
+    // which have the same last byte anyway)
+    let first = least_rotation(bytes);
+    let rotated = [&bytes[first..], &bytes[..first]].concat();
+    let indices = crate::suffix_array::suffix_array(&rotated)
+        .into_iter()
+        .map(|start| (start as usize + first) % n);
 
-    // Extract the last column and find the index of the original string
+    // Extract the last column and find the rows of the sampled rotations
     let mut last_column = Vec::with_capacity(n);
-    let mut original_index = 0;
+    let mut rows = vec![0; samples];
 
-    for (i, &rotation_start) in indices.iter().enumerate() {
+    for (i, rotation_start) in indices.enumerate() {
         // Last character of rotation starting at rotation_start
         last_column.push(bytes[(rotation_start + n - 1) % n]);
-        if rotation_start == 0 {
-            original_index = i;
+        if rotation_start % step == 0 {
+            rows[rotation_start / step] = i;
+        }
+    }
+
+    (last_column, rows)
+}
+
+/// Returns the start of the lexicographically least rotation of `bytes`, by
+/// Duval's Lyndon factorization of the input repeated twice.
+fn least_rotation(bytes: &[u8]) -> usize {
+    let n = bytes.len();
+    let at = |i: usize| bytes[i % n];
+
+    let mut i = 0;
+    let mut least = 0;
+
+    while i < n {
+        least = i;
+        let mut j = i + 1;
+        let mut k = i;
+
+        while j < 2 * n && at(k) <= at(j) {
+            k = if at(k) < at(j) { i } else { k + 1 };
+            j += 1;
+        }
+
+        while i <= k {
+            i += j - k;
         }
     }
 
-    (last_column, original_index)
+    least
 }
 
+/// Inverts [`bwt`] in linear time.
+///
+/// Sorting the last column by stable counting gives, for every row, the row
+/// that follows it and, as the first byte of that row, the byte to output.
+/// Both are packed into one entry, as in bzip2, so every output byte costs a
+/// single random memory access.
 pub fn ibwt(bytes: &[u8], index: usize) -> Vec<u8> {
-    if bytes.is_empty() {
-        return Vec::new();
+    ibwt_sampled(bytes, &[index])
+}
+
+/// Inverts [`bwt_sampled`] from the rows it returned.
+pub fn ibwt_sampled(bytes: &[u8], rows: &[usize]) -> Vec<u8> {
+    if bytes.len() <= 1 << 24 {
+        ibwt_packed::<u32>(bytes, rows)
+    } else {
+        ibwt_packed::<u64>(bytes, rows)
     }
+}
 
-    let n = bytes.len();
+/// A row number and a byte packed into one integer.
+trait Packed: Copy + Default {
+    fn pack(row: usize, byte: u8) -> Self;
+    fn row(self) -> usize;
+    fn byte(self) -> u8;
+}
+
+impl Packed for u32 {
+    fn pack(row: usize, byte: u8) -> Self {
+        (row as u32) << 8 | byte as u32
+    }
 
-    // Create first column by sorting the last column
-    let mut first_column = bytes.to_vec();
-    first_column.sort_unstable();
+    fn row(self) -> usize {
+        (self >> 8) as usize
+    }
+
+    fn byte(self) -> u8 {
+        self as u8
+    }
+}
 
-    // Build the transformation table
-    let mut next = vec![0; n];
-    let mut count = [0; 256];
+impl Packed for u64 {
+    fn pack(row: usize, byte: u8) -> Self {
+        (row as u64) << 8 | byte as u64
+    }
+
+    fn row(self) -> usize {
+        (self >> 8) as usize
+    }
+
+    fn byte(self) -> u8 {
+        self as u8
+    }
+}
+
+fn ibwt_packed<T: Packed>(bytes: &[u8], rows: &[usize]) -> Vec<u8> {
+    let n = bytes.len();
 
-    // Count occurrences of each character in the last column
+    // Starting row of each byte in the sorted first column
+    let mut start = [0; 256];
     for &byte in bytes {
-        count[byte as usize] += 1;
+        start[byte as usize] += 1;
     }
 
-    // Convert counts to starting positions
     let mut total = 0;
-    for i in 0..256 {
-        let temp = count[i];
-        count[i] = total;
-        total += temp;
+    for entry in &mut start {
+        let count = *entry;
+        *entry = total;
+        total += count;
     }
 
-    // Build the next array
-    for (i, &byte) in bytes.iter().enumerate() {
-        next[count[byte as usize]] = i;
-        count[byte as usize] += 1;
+    // The k-th occurrence of a byte in the last column is followed by the
+    // row of the k-th occurrence of it in the first column
+    let mut entries = vec![T::default(); n];
+
+    for (row, &byte) in bytes.iter().enumerate() {
+        entries[start[byte as usize]] = T::pack(row, byte);
+        start[byte as usize] += 1;
     }
 
-    // Reconstruct the original string
-    let mut result = Vec::with_capacity(n);
-    let mut current = index;
+    // Follow one chain per sampled row. The chains are independent, so their
+    // memory accesses overlap instead of waiting for each other
+    let step = n.div_ceil(rows.len());
+    let mut result = vec![0u8; n];
+    let mut current = rows.to_vec();
+
+    for offset in 0..step {
+        for (chain, row) in current.iter_mut().enumerate() {
+            let Some(byte) = result.get_mut(chain * step + offset) else {
+                continue;
+            };
 
-    for _ in 0..n {
-        result.push(first_column[current]);
-        current = next[current];
+            let entry = entries[*row];
+            *byte = entry.byte();
+            *row = entry.row();
+        }
     }
 
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As many rows as the BWT coder samples at most
+    const MAX_SAMPLES: usize = 16;
+
+    /// Sorts every rotation of `bytes` and returns the last column.
+    fn naive_bwt(bytes: &[u8]) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let n = bytes.len();
+        let mut rotations: Vec<Vec<u8>> = (0..n)
+            .map(|i| [&bytes[i..], &bytes[..i]].concat())
+            .collect();
+        rotations.sort();
+
+        let last_column = rotations.iter().map(|rotation| rotation[n - 1]).collect();
+        (last_column, rotations)
+    }
+
+    /// Bytes from a linear congruential generator, over an alphabet of
+    /// `alphabet` symbols so that small ones repeat a lot.
+    fn random(len: usize, alphabet: u32, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                ((state >> 33) as u32 % alphabet) as u8
+            })
+            .collect()
+    }
+
+    fn assert_matches_naive(bytes: &[u8]) {
+        let (last_column, index) = bwt(bytes);
+
+        if bytes.is_empty() {
+            assert!(last_column.is_empty());
+            return;
+        }
+
+        let (expected, rotations) = naive_bwt(bytes);
+        assert_eq!(last_column, expected, "{bytes:?}");
+
+        // Equal rotations may swap rows, but the row holds the input itself
+        assert_eq!(rotations[index], bytes, "{bytes:?}");
+    }
+
+    #[test]
+    fn matches_sorted_rotations_on_random_input() {
+        for (seed, alphabet) in (0..200).zip([2, 3, 4, 16, 256].into_iter().cycle()) {
+            let len = 1 + seed as usize * 7 % 300;
+            assert_matches_naive(&random(len, alphabet, seed));
+        }
+    }
+
+    #[test]
+    fn matches_sorted_rotations_on_repetitive_input() {
+        assert_matches_naive(b"");
+        assert_matches_naive(b"a");
+        assert_matches_naive(&[0; 100]);
+        assert_matches_naive(&[255; 100]);
+        assert_matches_naive(&b"ab".repeat(50));
+        assert_matches_naive(&b"abc".repeat(33));
+        assert_matches_naive(&b"aab".repeat(40));
+        assert_matches_naive(&[b"ab".repeat(20), b"a".to_vec()].concat());
+        assert_matches_naive(b"mississippi");
+        assert_matches_naive(b"banana");
+
+        // Fibonacci words, whose suffixes keep sharing long prefixes
+        let (mut a, mut b) = (b"a".to_vec(), b"ab".to_vec());
+        for _ in 0..10 {
+            (a, b) = (b.clone(), [b, a].concat());
+        }
+        assert_matches_naive(&b);
+    }
+
+    #[test]
+    fn inverts_with_every_sample_count() {
+        for len in [0, 1, 2, 15, 16, 17, 100, 1000, 4099] {
+            let bytes = random(len, 4, len as u64);
+
+            for samples in 1..=MAX_SAMPLES {
+                let (last_column, rows) = bwt_sampled(&bytes, samples);
+                assert_eq!(rows.len(), samples);
+
+                // Rows past the end of short inputs are never followed
+                assert_eq!(ibwt_sampled(&last_column, &rows), bytes, "{len} {samples}");
+            }
+        }
+    }
+
+    #[test]
+    fn wide_packing_matches_narrow() {
+        // Inputs over 16 MiB need 64-bit entries; the chains are the same
+        let bytes = random(5000, 16, 7);
+
+        for samples in [1, 3, 16] {
+            let (last_column, rows) = bwt_sampled(&bytes, samples);
+            assert_eq!(ibwt_packed::<u64>(&last_column, &rows), bytes);
+            assert_eq!(ibwt_packed::<u32>(&last_column, &rows), bytes);
+        }
+    }
+}
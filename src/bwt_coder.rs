@@ -1,77 +1,395 @@
-#![allow(unused)]
+use std::io::{self, Write};
 
-use std::{
-    io::{self, Cursor, Read, Write},
-    marker::PhantomData,
-    usize,
+use crate::{
+    codec::{Codec, CompressionOptions},
+    error::malformed,
 };
 
-const CHUNK_SIZE: usize = 1024 * 1024 * 8;
+pub const CHUNK_SIZE: usize = 1024 * 1024 * 8;
 
+// The chunk header holds the BWT index in its low bits and the run encoding
+// in the byte above them
+const INDEX_BITS: u32 = 24;
+
+// Bits of the run encoding byte holding the base 2 logarithm of the number
+// of evenly spaced positions whose rows are in the header. Every further
+// row lets the inverse BWT follow one more independent chain at once.
+const SAMPLES_SHIFT: u32 = 3;
+const SAMPLES_MASK: u8 = 0x38;
+const MAX_SAMPLES: usize = 16;
+
+// Chunk length per sampled row; shorter chunks only have the BWT index
+const SAMPLE_SPACING: usize = 64 * 1024;
+
+// Set in the run encoding byte when the chunk is shorter than `CHUNK_SIZE`
+// and its length follows the header and the rows, so that the decoder knows
+// where the next chunk starts
+const SIZED: u8 = 0x40;
+
+/// Most bytes [`BWTCoder`] writes for `len` bytes of input: two per byte for
+/// the runs, and per chunk, which may be a single byte, a header, a length
+/// and the sampled rows, one per 64 KiB.
+pub(crate) fn max_encoded_len(len: usize) -> usize {
+    len.saturating_mul(10)
+}
+
+/// How runs in the move-to-front output of a chunk are coded.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum RunEncoding {
+    /// Every run as a `(length, byte)` pair, with lengths up to 255.
+    #[default]
+    Pairs,
+    /// Runs of zeros in bijective base 2 with the digits 0 (RUNA) and 1
+    /// (RUNB), as in bzip2. Other values are shifted up by one; 254 and 255
+    /// become 255 followed by a byte of 0 or 1.
+    ZeroRuns,
+}
+
+impl RunEncoding {
+    fn id(self) -> u8 {
+        match self {
+            RunEncoding::Pairs => 0,
+            RunEncoding::ZeroRuns => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(RunEncoding::Pairs),
+            1 => Some(RunEncoding::ZeroRuns),
+            _ => None,
+        }
+    }
+}
+
+/// BWT followed by move-to-front and run-length coding, in chunks of
+/// [`CHUNK_SIZE`] unless the options ask for smaller ones.
+///
+/// Every chunk starts with a big-endian `u32` holding the BWT index in its
+/// low 24 bits and the [`RunEncoding`] in its high 8 bits. The encoding is
+/// read from there when decoding, so it does not have to match the coder.
+/// Bits 3 to 5 of the encoding hold the base 2 logarithm of a sample count,
+/// one per 64 KiB of the chunk up to 16, and one `u32` fewer than that
+/// follows with the rows of the rotations starting at the other evenly
+/// spaced positions. With bit 6 set, one more `u32` follows with the length
+/// of the chunk.
 pub struct BWTCoder {
-    p: PhantomData<()>,
+    run_encoding: RunEncoding,
+    chunk_size: usize,
 }
 
 impl BWTCoder {
     pub fn new() -> Self {
-        BWTCoder { p: PhantomData }
+        BWTCoder {
+            run_encoding: RunEncoding::Pairs,
+            chunk_size: CHUNK_SIZE,
+        }
+    }
+
+    /// Cuts the input into chunks of the block size of `options`.
+    pub fn with_options(mut self, options: &CompressionOptions) -> Self {
+        self.chunk_size = options.block_size().min(CHUNK_SIZE);
+        self
+    }
+
+    pub fn with_run_encoding(mut self, run_encoding: RunEncoding) -> Self {
+        self.run_encoding = run_encoding;
+        self
+    }
+}
+
+impl Default for BWTCoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Codec for BWTCoder {
+    fn name(&self) -> &'static str {
+        "bwt"
     }
 
-    pub fn encode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+    fn id(&self) -> u8 {
+        2
+    }
+
+    fn encode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
         let mut output = Vec::new();
-        let mut writer = Cursor::new(&mut output);
 
-        for chunk in bytes.chunks(CHUNK_SIZE) {
-            let (bwt, index) = crate::bwt::bwt(chunk);
-            writer.write(&(index as u32).to_be_bytes())?;
+        let sized = self.chunk_size < CHUNK_SIZE;
 
-            let mtf = crate::mtf::mtf(&bwt);
-            let data = mtf;
-
-            let mut curr = data[0];
-            let mut len = 1;
-
-            for &byte in &data[1..] {
-                if curr == byte && len < 255 {
-                    len += 1;
-                } else {
-                    writer.write(&[len as u8, curr])?;
-                    curr = byte;
-                    len = 1;
-                }
+        for chunk in bytes.chunks(self.chunk_size) {
+            let samples = (chunk.len() / SAMPLE_SPACING).clamp(1, MAX_SAMPLES);
+            let samples = 1 << samples.ilog2();
+
+            let (bwt, rows) = crate::bwt::bwt_sampled(chunk, samples);
+            let mut flags = self.run_encoding.id() | (samples.ilog2() as u8) << SAMPLES_SHIFT;
+
+            if sized {
+                flags |= SIZED;
+            }
+
+            output.write_all(&((flags as u32) << INDEX_BITS | rows[0] as u32).to_be_bytes())?;
+
+            for &row in &rows[1..] {
+                output.write_all(&(row as u32).to_be_bytes())?;
+            }
+
+            if sized {
+                output.write_all(&(chunk.len() as u32).to_be_bytes())?;
             }
 
-            writer.write(&[len as u8, curr])?;
+            let mtf = crate::mtf::mtf(&bwt);
+
+            match self.run_encoding {
+                RunEncoding::Pairs => encode_pairs(&mtf, &mut output),
+                RunEncoding::ZeroRuns => encode_zero_runs(&mtf, &mut output),
+            }
         }
 
         Ok(output)
     }
 
-    pub fn decode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+    fn decode(&self, bytes: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
         let mut output = Vec::new();
-        let mut reader = Cursor::new(bytes);
+        let mut input = bytes;
+
+        while let Some((header, rest)) = input.split_first_chunk::<4>() {
+            input = rest;
+
+            let header = u32::from_be_bytes(*header);
+            let flags = (header >> INDEX_BITS) as u8;
+            let run_encoding = RunEncoding::from_id(flags & !(SAMPLES_MASK | SIZED))
+                .ok_or_else(|| malformed("Unknown run encoding"))?;
+
+            let samples = 1 << ((flags & SAMPLES_MASK) >> SAMPLES_SHIFT);
 
-        let mut index_bytes = [0u8; 4];
+            if samples > MAX_SAMPLES {
+                return Err(malformed("Invalid number of BWT samples"));
+            }
+
+            let mut rows = vec![(header & ((1 << INDEX_BITS) - 1)) as usize];
+
+            for _ in 1..samples {
+                let (row, rest) = input
+                    .split_first_chunk::<4>()
+                    .ok_or_else(|| malformed("Truncated BWT chunk header"))?;
+
+                rows.push(u32::from_be_bytes(*row) as usize);
+                input = rest;
+            }
+
+            let mut size = CHUNK_SIZE;
+
+            if flags & SIZED != 0 {
+                let (length, rest) = input
+                    .split_first_chunk::<4>()
+                    .ok_or_else(|| malformed("Truncated BWT chunk header"))?;
 
-        while reader.read(&mut index_bytes)? == index_bytes.len() {
-            let index = u32::from_be_bytes(index_bytes);
-            let mut chunk = Vec::new();
+                size = u32::from_be_bytes(*length) as usize;
+                input = rest;
 
-            while chunk.len() < CHUNK_SIZE {
-                let mut header = [0u8; 2];
-                if reader.read(&mut header)? < 2 {
-                    break;
+                if size > CHUNK_SIZE {
+                    return Err(malformed("Invalid BWT chunk length"));
                 }
+            }
+
+            // No chunk decodes past the end of the block
+            let limit = size.min(max_len - output.len());
+
+            let chunk = match run_encoding {
+                RunEncoding::Pairs => decode_pairs(&mut input, limit),
+                RunEncoding::ZeroRuns => decode_zero_runs(&mut input, limit)?,
+            };
+
+            if chunk.len() > limit {
+                return Err(malformed("BWT chunk longer than the block"));
+            }
+
+            if flags & SIZED != 0 && chunk.len() != size {
+                return Err(malformed("BWT chunk length mismatch"));
+            }
 
-                let [len, byte] = header;
-                chunk.extend(std::iter::repeat_n(byte, len as usize));
+            if rows.iter().any(|&row| row >= chunk.len()) {
+                return Err(malformed("BWT index out of range"));
             }
 
             let data = crate::mtf::imtf(&chunk);
-            let data = crate::bwt::ibwt(&data, index as usize);
+            let data = crate::bwt::ibwt_sampled(&data, &rows);
             output.extend(data);
         }
 
         Ok(output)
     }
 }
+
+fn encode_pairs(data: &[u8], output: &mut Vec<u8>) {
+    let mut curr = data[0];
+    let mut len = 1;
+
+    for &byte in &data[1..] {
+        if curr == byte && len < 255 {
+            len += 1;
+        } else {
+            output.extend_from_slice(&[len as u8, curr]);
+            curr = byte;
+            len = 1;
+        }
+    }
+
+    output.extend_from_slice(&[len as u8, curr]);
+}
+
+/// Reads pairs until the chunk holds `size` bytes or the input ends.
+fn decode_pairs(input: &mut &[u8], size: usize) -> Vec<u8> {
+    let mut chunk = Vec::new();
+
+    while chunk.len() < size {
+        let Some((&[len, byte], rest)) = input.split_first_chunk::<2>() else {
+            break;
+        };
+
+        *input = rest;
+        chunk.extend(std::iter::repeat_n(byte, len as usize));
+    }
+
+    chunk
+}
+
+fn encode_zero_runs(data: &[u8], output: &mut Vec<u8>) {
+    let mut zeros = 0;
+
+    for &value in data {
+        if value == 0 {
+            zeros += 1;
+            continue;
+        }
+
+        push_zero_run(zeros, output);
+        zeros = 0;
+
+        if value < 254 {
+            output.push(value + 1);
+        } else {
+            output.extend_from_slice(&[255, value - 254]);
+        }
+    }
+
+    push_zero_run(zeros, output);
+}
+
+/// Writes `run` in bijective base 2, least significant digit first, with the
+/// digits 1 (RUNA) and 2 (RUNB) as the symbols 0 and 1.
+pub(crate) fn push_zero_run<T: From<u8>>(mut run: usize, output: &mut Vec<T>) {
+    while run > 0 {
+        let digit = 2 - run % 2;
+        output.push((digit as u8 - 1).into());
+        run = (run - digit) / 2;
+    }
+}
+
+/// Reads symbols until the chunk holds `size` bytes or the input ends.
+fn decode_zero_runs(input: &mut &[u8], size: usize) -> io::Result<Vec<u8>> {
+    let mut chunk = Vec::new();
+    let mut run = 0;
+    let mut digit = 0;
+
+    while chunk.len() + run < size {
+        let Some((&symbol, rest)) = input.split_first() else {
+            break;
+        };
+
+        *input = rest;
+
+        if symbol < 2 {
+            run += (symbol as usize + 1) << digit;
+            digit += 1;
+
+            if chunk.len() + run > size {
+                return Err(malformed("Invalid zero run"));
+            }
+
+            continue;
+        }
+
+        chunk.extend(std::iter::repeat_n(0, run));
+        run = 0;
+        digit = 0;
+
+        let value = if symbol < 255 {
+            symbol - 1
+        } else {
+            let Some((&low, rest)) = input.split_first().filter(|&(&low, _)| low < 2) else {
+                return Err(malformed("Invalid escaped value"));
+            };
+
+            *input = rest;
+            254 + low
+        };
+
+        chunk.push(value);
+    }
+
+    chunk.extend(std::iter::repeat_n(0, run));
+
+    Ok(chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lengths on both sides of every power of two up to 2^20.
+    fn run_lengths() -> impl Iterator<Item = usize> {
+        (0..=20).flat_map(|bits| {
+            let power = 1usize << bits;
+            [power - 1, power, power + 1]
+        })
+    }
+
+    #[test]
+    fn zero_runs_use_bijective_base_2() {
+        for run in run_lengths().chain(1..100) {
+            let mut digits = Vec::<u8>::new();
+            push_zero_run(run, &mut digits);
+
+            // RUNA is worth 1 and RUNB 2 at every position
+            let value: usize = digits
+                .iter()
+                .enumerate()
+                .map(|(position, &digit)| (digit as usize + 1) << position)
+                .sum();
+            assert_eq!(value, run);
+
+            // One digit per bit of `run + 1` but the leading one
+            assert_eq!(digits.len(), (run + 1).ilog2() as usize);
+        }
+    }
+
+    #[test]
+    fn zero_runs_round_trip() {
+        for run in run_lengths() {
+            // Runs alone, and between values that need escaping
+            for data in [
+                vec![0; run],
+                [&[254][..], &vec![0; run], &[255, 1]].concat(),
+            ] {
+                let mut encoded = Vec::new();
+                encode_zero_runs(&data, &mut encoded);
+
+                let mut input = &encoded[..];
+                assert_eq!(decode_zero_runs(&mut input, data.len()).unwrap(), data);
+                assert!(input.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn zero_runs_stop_at_the_chunk_size() {
+        let mut encoded = Vec::new();
+        encode_zero_runs(&[0; 1000], &mut encoded);
+
+        let mut input = &encoded[..];
+        assert!(decode_zero_runs(&mut input, 999).is_err());
+    }
+}
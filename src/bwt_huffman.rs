@@ -1,19 +1,44 @@
-use std::{io, marker::PhantomData};
+use std::io;
 
-use crate::{bwt_coder::BWTCoder, huffman::HuffmanCoder};
+use crate::{
+    bwt_coder::{BWTCoder, RunEncoding, max_encoded_len},
+    codec::{Codec, CompressionOptions},
+    huffman::HuffmanCoder,
+};
 
+#[derive(Default)]
 pub struct BWTHuffmanCoder {
-    p: PhantomData<()>,
+    options: CompressionOptions,
 }
 
 impl BWTHuffmanCoder {
     pub fn new() -> Self {
-        BWTHuffmanCoder { p: PhantomData }
+        BWTHuffmanCoder {
+            options: CompressionOptions::new(),
+        }
     }
 
-    pub fn encode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
-        let bwt_coder = BWTCoder::new();
-        let huffman_coder = HuffmanCoder::new();
+    /// Encodes with the chunk size and model order of `options`.
+    pub fn with_options(mut self, options: &CompressionOptions) -> Self {
+        self.options = *options;
+        self
+    }
+}
+
+impl Codec for BWTHuffmanCoder {
+    fn name(&self) -> &'static str {
+        "bwt-huffman"
+    }
+
+    fn id(&self) -> u8 {
+        3
+    }
+
+    fn encode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let bwt_coder = BWTCoder::new()
+            .with_run_encoding(RunEncoding::ZeroRuns)
+            .with_options(&self.options);
+        let huffman_coder = HuffmanCoder::new().with_options(&self.options);
 
         let bwt_encoded = bwt_coder.encode(bytes)?;
         let huffman_encoded = huffman_coder.encode(&bwt_encoded)?;
@@ -21,12 +46,12 @@ impl BWTHuffmanCoder {
         Ok(huffman_encoded)
     }
 
-    pub fn decode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+    fn decode(&self, bytes: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
         let bwt_coder = BWTCoder::new();
         let huffman_coder = HuffmanCoder::new();
 
-        let huffman_decoded = huffman_coder.decode(bytes)?;
-        let bwt_decoded = bwt_coder.decode(&huffman_decoded)?;
+        let huffman_decoded = huffman_coder.decode(bytes, max_encoded_len(max_len))?;
+        let bwt_decoded = bwt_coder.decode(&huffman_decoded, max_len)?;
 
         Ok(bwt_decoded)
     }
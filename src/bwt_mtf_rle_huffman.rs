@@ -1,73 +1,84 @@
-#![allow(unused)]
-
-use std::io::{Cursor, Read, Write};
-use std::{io, marker::PhantomData};
-
-use bitbit::{BitReader, BitWriter, MSB};
-
-use crate::bwt_coder::BWTCoder;
-use crate::huffman::TreeNode;
-
+use std::io;
+use std::io::{Cursor, Write};
+
+use bitbit::BitWriter;
+
+use crate::bit_reader::BitReader;
+use crate::bwt_coder::{BWTCoder, RunEncoding, max_encoded_len};
+use crate::codec::{Codec, CompressionOptions};
+use crate::huffman::{CanonicalCode, DEFAULT_MAX_CODE_LENGTH};
+use crate::multi_table;
+use crate::varint;
+
+/// Huffman codes the run lengths and move-to-front values of [`BWTCoder`].
+///
+/// By default each is coded with a code per previous value. The multi-table
+/// back end instead codes the zero runs of [`RunEncoding::ZeroRuns`] as one
+/// stream with up to six bzip2-style tables, which keeps the header small.
+#[derive(Default)]
 pub struct BwtMtfRleHuffmanCoder {
-    p: PhantomData<()>,
+    multi_table: bool,
+    options: CompressionOptions,
 }
 
 impl BwtMtfRleHuffmanCoder {
     pub fn new() -> Self {
-        BwtMtfRleHuffmanCoder { p: PhantomData }
+        BwtMtfRleHuffmanCoder {
+            multi_table: false,
+            options: CompressionOptions::new(),
+        }
     }
 
-    pub fn encode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
-        let bwt_coder = BWTCoder::new();
-        let bwt = bwt_coder.encode(bytes)?;
+    /// Encodes with the chunk size and table count of `options`.
+    pub fn with_options(mut self, options: &CompressionOptions) -> Self {
+        self.options = *options;
+        self
+    }
+
+    /// Uses the multi-table back end, registered as `bwt-multi-huffman`.
+    pub fn with_multi_table(mut self, multi_table: bool) -> Self {
+        self.multi_table = multi_table;
+        self
+    }
 
+    fn encode_contexts<W: Write>(&self, writer: &mut BitWriter<W>, bwt: &[u8]) -> io::Result<()> {
         let mut length_length_frequencies = vec![[0u64; 256]; 256];
         let mut byte_byte_frequencies = vec![[0u64; 256]; 256];
 
-        for window in bwt.windows(4).step_by(2) {
-            let &[l1, b1, l2, b2] = window else {
+        // Count every pair in the context it is coded in, starting from 0
+        let mut previous_length = 0u8;
+        let mut previous_byte = 0u8;
+
+        for window in bwt.chunks(2) {
+            let &[length, byte] = window else {
                 unreachable!();
             };
 
-            length_length_frequencies[l1 as usize][l2 as usize] += 1;
-            byte_byte_frequencies[b1 as usize][b2 as usize] += 1;
+            length_length_frequencies[previous_length as usize][length as usize] += 1;
+            byte_byte_frequencies[previous_byte as usize][byte as usize] += 1;
+
+            previous_length = length;
+            previous_byte = byte;
         }
 
-        let length_length_trees = length_length_frequencies
-            .into_iter()
-            .map(|frequencies| TreeNode::build(&frequencies).unwrap())
+        let length_length_codes = length_length_frequencies
+            .iter()
+            .map(|frequencies| {
+                CanonicalCode::from_frequencies(frequencies, DEFAULT_MAX_CODE_LENGTH)
+            })
             .collect::<Vec<_>>();
 
-        let byte_byte_trees = byte_byte_frequencies
-            .into_iter()
-            .map(|frequencies| TreeNode::build(&frequencies).unwrap())
+        let byte_byte_codes = byte_byte_frequencies
+            .iter()
+            .map(|frequencies| {
+                CanonicalCode::from_frequencies(frequencies, DEFAULT_MAX_CODE_LENGTH)
+            })
             .collect::<Vec<_>>();
 
-        let mut output = Vec::new();
-        let mut output_cursor = Cursor::new(&mut output);
-
-        output_cursor.write(&bwt.len().to_be_bytes())?;
-
-        let mut writer = BitWriter::new(output_cursor);
-
-        for tree in &length_length_trees {
-            tree.encode(&mut writer)?;
-        }
-
-        for tree in &byte_byte_trees {
-            tree.encode(&mut writer)?;
+        for code in length_length_codes.iter().chain(&byte_byte_codes) {
+            code.write(writer)?;
         }
 
-        let length_length_codes = length_length_trees
-            .into_iter()
-            .map(|tree| tree.codes())
-            .collect::<Vec<_>>();
-
-        let byte_byte_codes = byte_byte_trees
-            .into_iter()
-            .map(|tree| tree.codes())
-            .collect::<Vec<_>>();
-
         let mut previous_length = 0u8;
         let mut previous_byte = 0u8;
 
@@ -76,40 +87,30 @@ impl BwtMtfRleHuffmanCoder {
                 unreachable!()
             };
 
-            let length_code = length_length_codes[previous_length as usize][length as usize];
-            let byte_code = byte_byte_codes[previous_byte as usize][byte as usize];
+            let length_code = length_length_codes[previous_length as usize].code(length.into());
+            let byte_code = byte_byte_codes[previous_byte as usize].code(byte.into());
 
-            length_code.encode(&mut writer)?;
-            byte_code.encode(&mut writer)?;
+            length_code.encode(writer)?;
+            byte_code.encode(writer)?;
 
             previous_length = length;
             previous_byte = byte;
         }
 
-        writer.pad_to_byte()?;
-
-        Ok(output)
+        Ok(())
     }
 
-    pub fn decode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
-        let mut input_cursor = Cursor::new(bytes);
-
-        let mut length_bytes = [0u8; 8];
-        input_cursor.read(&mut length_bytes)?;
-        let length = usize::from_be_bytes(length_bytes);
-
-        let mut reader = BitReader::<_, MSB>::new(input_cursor);
-
-        let mut length_length_trees = Vec::new();
+    fn decode_contexts(&self, reader: &mut BitReader, pairs: usize) -> io::Result<Vec<u8>> {
+        let mut length_length_codes = Vec::with_capacity(256);
 
         for _ in 0..256 {
-            length_length_trees.push(TreeNode::decode(&mut reader)?);
+            length_length_codes.push(CanonicalCode::read(reader, 256)?);
         }
 
-        let mut byte_byte_trees = Vec::new();
+        let mut byte_byte_codes = Vec::with_capacity(256);
 
         for _ in 0..256 {
-            byte_byte_trees.push(TreeNode::decode(&mut reader)?);
+            byte_byte_codes.push(CanonicalCode::read(reader, 256)?);
         }
 
         let mut output = Vec::new();
@@ -117,11 +118,10 @@ impl BwtMtfRleHuffmanCoder {
         let mut previous_length = 0u8;
         let mut previous_byte = 0u8;
 
-        for _ in 0..length / 2 {
-            let length =
-                length_length_trees[previous_length as usize].decode_symbol(&mut reader)?;
+        for _ in 0..pairs {
+            let length = length_length_codes[previous_length as usize].decode_symbol(reader)? as u8;
 
-            let byte = byte_byte_trees[previous_byte as usize].decode_symbol(&mut reader)?;
+            let byte = byte_byte_codes[previous_byte as usize].decode_symbol(reader)? as u8;
 
             output.push(length);
             output.push(byte);
@@ -130,8 +130,84 @@ impl BwtMtfRleHuffmanCoder {
             previous_byte = byte;
         }
 
+        Ok(output)
+    }
+
+    fn encode_multi_table<W: Write>(
+        &self,
+        writer: &mut BitWriter<W>,
+        bwt: &[u8],
+    ) -> io::Result<()> {
+        let symbols: Vec<u16> = bwt.iter().map(|&byte| byte.into()).collect();
+        multi_table::encode(writer, &symbols, 256, self.options.max_tables())
+    }
+
+    fn decode_multi_table(&self, reader: &mut BitReader, length: usize) -> io::Result<Vec<u8>> {
+        let symbols = multi_table::decode(reader, length, 256)?;
+        Ok(symbols.into_iter().map(|symbol| symbol as u8).collect())
+    }
+}
+
+impl Codec for BwtMtfRleHuffmanCoder {
+    fn name(&self) -> &'static str {
+        if self.multi_table {
+            "bwt-multi-huffman"
+        } else {
+            "bwt-mtf-rle-huffman"
+        }
+    }
+
+    fn id(&self) -> u8 {
+        if self.multi_table { 11 } else { 5 }
+    }
+
+    fn encode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        // The context back end models the output as (length, byte) pairs,
+        // the multi-table one codes zero runs like bzip2
+        let run_encoding = if self.multi_table {
+            RunEncoding::ZeroRuns
+        } else {
+            RunEncoding::Pairs
+        };
+
+        let bwt_coder = BWTCoder::new()
+            .with_run_encoding(run_encoding)
+            .with_options(&self.options);
+        let bwt = bwt_coder.encode(bytes)?;
+
+        let mut output = Vec::new();
+        let mut output_cursor = Cursor::new(&mut output);
+
+        varint::write_len(&mut output_cursor, bwt.len())?;
+
+        let mut writer = BitWriter::new(output_cursor);
+
+        if self.multi_table {
+            self.encode_multi_table(&mut writer, &bwt)?;
+        } else {
+            self.encode_contexts(&mut writer, &bwt)?;
+        }
+
+        writer.pad_to_byte()?;
+
+        Ok(output)
+    }
+
+    fn decode(&self, bytes: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        let mut input_cursor = Cursor::new(bytes);
+
+        let length = varint::read_len_at_most(&mut input_cursor, max_encoded_len(max_len))?;
+
+        let mut reader = BitReader::new(&bytes[input_cursor.position() as usize..]);
+
+        let output = if self.multi_table {
+            self.decode_multi_table(&mut reader, length)?
+        } else {
+            self.decode_contexts(&mut reader, length / 2)?
+        };
+
         let bwt_coder = BWTCoder::new();
-        let output = bwt_coder.decode(&output)?;
+        let output = bwt_coder.decode(&output, max_len)?;
 
         Ok(output)
     }
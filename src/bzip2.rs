@@ -0,0 +1,599 @@
+//! Reading and writing `.bz2` streams, compatible with the `bzip2` tool.
+//!
+//! A block is built from the input by replacing runs of 4 to 255 equal bytes
+//! with four bytes and the number of further repeats, then sorted with the
+//! BWT. The last column is move-to-front coded, with runs of zeros written
+//! in bijective base 2 as the digits RUNA and RUNB, and Huffman coded with up
+//! to six tables selected per group of 50 symbols. Every block carries the
+//! CRC of its input, and the stream ends with a CRC combined from them.
+
+use std::io::{self, Write};
+
+use crate::{
+    bit_reader::BitReader,
+    bwt::{bwt, ibwt},
+    bwt_coder::push_zero_run,
+    error::{CorruptionError, malformed},
+    huffman::CanonicalCode,
+    multi_table::{
+        GROUP_SIZE, MAX_TABLES, MIN_TABLES, TableBuilder, read_selectors, selector_positions,
+    },
+};
+
+pub const MIN_LEVEL: u32 = 1;
+pub const MAX_LEVEL: u32 = 9;
+
+const BLOCK_MAGIC: u64 = 0x3141_5926_5359;
+const END_MAGIC: u64 = 0x1772_4538_5090;
+
+// Longest code the format allows, and the longest bzip2 writes
+const MAX_CODE_LENGTH: u32 = 20;
+const ENCODE_MAX_CODE_LENGTH: usize = 17;
+
+const RUNA: u16 = 0;
+const RUNB: u16 = 1;
+
+// Digits of the longest zero run a block can hold, with one to spare
+const MAX_RUN_DIGITS: u32 = 21;
+
+/// Block size in bytes of the run-length coded input for `level`; bzip2
+/// leaves a few bytes of headroom below the nominal size.
+fn block_size(level: u32) -> usize {
+    level as usize * 100_000 - 19
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+};
+
+/// The CRC-32 variant of bzip2, which shifts the most significant bit first.
+struct Crc {
+    crc: u32,
+}
+
+impl Crc {
+    fn new() -> Self {
+        Crc { crc: !0 }
+    }
+
+    fn update(&mut self, byte: u8) {
+        self.crc = (self.crc << 8) ^ CRC_TABLE[((self.crc >> 24) ^ byte as u32) as usize];
+    }
+
+    fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
+
+fn combine(combined: u32, block_crc: u32) -> u32 {
+    combined.rotate_left(1) ^ block_crc
+}
+
+/// Collects bits most significant first. Blocks are not byte aligned, so the
+/// bits that do not fill a byte are carried over to the next block.
+struct BitSink {
+    bytes: Vec<u8>,
+    bits: u64,
+    filled: u32,
+}
+
+impl BitSink {
+    fn write_bits(&mut self, value: u32, count: u32) {
+        self.bits = (self.bits << count) | value as u64;
+        self.filled += count;
+
+        while self.filled >= 8 {
+            self.filled -= 8;
+            self.bytes.push((self.bits >> self.filled) as u8);
+        }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        self.write_bits(bit as u32, 1);
+    }
+
+    fn write_u48(&mut self, value: u64) {
+        self.write_bits((value >> 24) as u32, 24);
+        self.write_bits(value as u32 & 0xFF_FFFF, 24);
+    }
+
+    fn pad_to_byte(&mut self) {
+        if self.filled > 0 {
+            self.write_bits(0, 8 - self.filled);
+        }
+    }
+}
+
+/// Compresses everything written to it into a `.bz2` stream in `W`.
+///
+/// Blocks are written as they fill up; the stream is completed by
+/// [`Encoder::finish`].
+pub struct Encoder<W: Write> {
+    writer: W,
+    sink: BitSink,
+    block_size: usize,
+    block: Vec<u8>,
+    run_byte: u8,
+    run_length: usize,
+    crc: Crc,
+    combined: u32,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Writes the stream header. `level` sets the block size to `level` times
+    /// 100 000 bytes, like the `-1` to `-9` options of bzip2.
+    ///
+    /// Panics unless `level` is between 1 and 9.
+    pub fn new(mut writer: W, level: u32) -> io::Result<Self> {
+        assert!((MIN_LEVEL..=MAX_LEVEL).contains(&level));
+
+        writer.write_all(&[b'B', b'Z', b'h', b'0' + level as u8])?;
+
+        Ok(Encoder {
+            writer,
+            sink: BitSink {
+                bytes: Vec::new(),
+                bits: 0,
+                filled: 0,
+            },
+            block_size: block_size(level),
+            block: Vec::new(),
+            run_byte: 0,
+            run_length: 0,
+            crc: Crc::new(),
+            combined: 0,
+        })
+    }
+
+    /// Writes the pending block and the end of the stream, and returns the
+    /// underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.end_run();
+
+        if !self.block.is_empty() {
+            self.write_block();
+        }
+
+        self.sink.write_u48(END_MAGIC);
+        self.sink.write_bits(self.combined, 32);
+        self.sink.pad_to_byte();
+
+        self.writer.write_all(&self.sink.bytes)?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    fn put(&mut self, byte: u8) -> io::Result<()> {
+        if self.run_length > 0 && byte == self.run_byte && self.run_length < 255 {
+            self.run_length += 1;
+            self.crc.update(byte);
+            return Ok(());
+        }
+
+        self.end_run();
+
+        if self.block.len() >= self.block_size {
+            self.write_block();
+            self.writer.write_all(&self.sink.bytes)?;
+            self.sink.bytes.clear();
+        }
+
+        self.run_byte = byte;
+        self.run_length = 1;
+        self.crc.update(byte);
+
+        Ok(())
+    }
+
+    /// Adds the pending run to the block: up to four bytes, and for a run of
+    /// four or more the number of further repeats.
+    fn end_run(&mut self) {
+        let length = self.run_length;
+
+        self.block
+            .extend(std::iter::repeat_n(self.run_byte, length.min(4)));
+
+        if length >= 4 {
+            self.block.push((length - 4) as u8);
+        }
+
+        self.run_length = 0;
+    }
+
+    fn write_block(&mut self) {
+        let block_crc = self.crc.finish();
+        self.combined = combine(self.combined, block_crc);
+        self.crc = Crc::new();
+
+        let (last_column, original_index) = bwt(&self.block);
+
+        let mut in_use = [false; 256];
+        for &byte in &self.block {
+            in_use[byte as usize] = true;
+        }
+
+        let symbols = mtf_runs(&last_column, &in_use);
+        let alphabet_size = in_use.iter().filter(|&&used| used).count() + 2;
+
+        // The format has a code length for every symbol of the alphabet
+        let (tables, selectors) = TableBuilder::new(alphabet_size)
+            .with_max_code_length(ENCODE_MAX_CODE_LENGTH)
+            .with_every_symbol(true)
+            .build(&symbols);
+
+        let sink = &mut self.sink;
+
+        sink.write_u48(BLOCK_MAGIC);
+        sink.write_bits(block_crc, 32);
+        // Not randomised
+        sink.write_bit(false);
+        sink.write_bits(original_index as u32, 24);
+
+        // Bitmap of the byte ranges of 16 in use, then of the bytes in each
+        let ranges: Vec<bool> = in_use
+            .chunks(16)
+            .map(|range| range.contains(&true))
+            .collect();
+
+        for &used in &ranges {
+            sink.write_bit(used);
+        }
+
+        for (range, _) in in_use.chunks(16).zip(&ranges).filter(|&(_, &used)| used) {
+            for &used in range {
+                sink.write_bit(used);
+            }
+        }
+
+        sink.write_bits(tables.len() as u32, 3);
+        sink.write_bits(selectors.len() as u32, 15);
+
+        for position in selector_positions(&selectors, tables.len()) {
+            for _ in 0..position {
+                sink.write_bit(true);
+            }
+            sink.write_bit(false);
+        }
+
+        // Code lengths as a start value and changes: 10 adds one, 11
+        // subtracts one and 0 moves on to the next symbol
+        for table in &tables {
+            let lengths: Vec<u32> = (0..alphabet_size as u16)
+                .map(|symbol| table.length(symbol) as u32)
+                .collect();
+
+            let mut current = lengths[0];
+            sink.write_bits(current, 5);
+
+            for &length in &lengths {
+                while current < length {
+                    sink.write_bits(0b10, 2);
+                    current += 1;
+                }
+
+                while current > length {
+                    sink.write_bits(0b11, 2);
+                    current -= 1;
+                }
+
+                sink.write_bit(false);
+            }
+        }
+
+        for (group, &selector) in symbols.chunks(GROUP_SIZE).zip(&selectors) {
+            let table = &tables[selector as usize];
+
+            for &symbol in group {
+                let code = table.code(symbol);
+                sink.write_bits(code.word as u32, code.len as u32);
+            }
+        }
+
+        self.block.clear();
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.put(byte)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    /// Passes on the completed blocks. The block being filled is only
+    /// written once it is full or the stream is finished.
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Compresses `bytes` into a `.bz2` stream with the block size of `level`.
+pub fn compress(bytes: &[u8], level: u32) -> io::Result<Vec<u8>> {
+    let mut encoder = Encoder::new(Vec::new(), level)?;
+    encoder.write_all(bytes)?;
+    encoder.finish()
+}
+
+/// Move-to-front codes the last column over the bytes in use, writes zero
+/// runs with RUNA and RUNB, shifts the other positions up by one and ends
+/// with the end of block symbol.
+fn mtf_runs(last_column: &[u8], in_use: &[bool; 256]) -> Vec<u16> {
+    let mut order: Vec<u8> = (0..=255).filter(|&byte| in_use[byte as usize]).collect();
+    let end_of_block = order.len() as u16 + 1;
+
+    let mut symbols = Vec::with_capacity(last_column.len() + 1);
+    let mut zeros = 0;
+
+    for &byte in last_column {
+        let position = order.iter().position(|&x| x == byte).unwrap();
+
+        if position == 0 {
+            zeros += 1;
+            continue;
+        }
+
+        push_zero_run(zeros, &mut symbols);
+        zeros = 0;
+
+        order[..=position].rotate_right(1);
+        symbols.push(position as u16 + 1);
+    }
+
+    push_zero_run(zeros, &mut symbols);
+    symbols.push(end_of_block);
+
+    symbols
+}
+
+/// Decompresses one or more concatenated `.bz2` streams.
+pub fn decompress(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut reader = BitReader::new(bytes);
+    let mut output = Vec::new();
+
+    loop {
+        let magic = reader.read_bits(24)?;
+        let level = reader.read_bits(8)?;
+
+        if magic != 0x42_5A_68 || !(MIN_LEVEL..=MAX_LEVEL).contains(&level.wrapping_sub(0x30)) {
+            return Err(malformed("Not a bzip2 stream"));
+        }
+
+        let block_size = block_size(level - 0x30) + 19;
+        let mut combined = 0;
+        let mut blocks = 0;
+
+        loop {
+            let magic = ((reader.read_bits(24)? as u64) << 24) | reader.read_bits(24)? as u64;
+
+            match magic {
+                BLOCK_MAGIC => {
+                    let block_crc = decode_block(&mut reader, block_size, blocks, &mut output)?;
+                    combined = combine(combined, block_crc);
+                    blocks += 1;
+                }
+
+                END_MAGIC => {
+                    let expected = reader.read_bits(32)?;
+
+                    if expected != combined {
+                        return Err(CorruptionError::ContentChecksum {
+                            expected,
+                            actual: combined,
+                        }
+                        .into());
+                    }
+
+                    break;
+                }
+
+                _ => return Err(malformed("Invalid bzip2 block")),
+            }
+        }
+
+        reader.align_to_byte()?;
+
+        if reader.is_empty() {
+            return Ok(output);
+        }
+    }
+}
+
+/// Decodes a block after its magic into `output`, and returns its CRC.
+fn decode_block(
+    reader: &mut BitReader,
+    block_size: usize,
+    block: usize,
+    output: &mut Vec<u8>,
+) -> io::Result<u32> {
+    let expected = reader.read_bits(32)?;
+
+    if reader.read_bit()? {
+        return Err(malformed("Randomised bzip2 blocks are not supported"));
+    }
+
+    let original_index = reader.read_bits(24)? as usize;
+
+    let ranges = reader.read_bits(16)?;
+    let mut bytes_in_use = Vec::new();
+
+    for range in (0..16).filter(|&range| ranges & (0x8000 >> range) != 0) {
+        let used = reader.read_bits(16)?;
+
+        for byte in (0..16).filter(|&byte| used & (0x8000 >> byte) != 0) {
+            bytes_in_use.push((range * 16 + byte) as u8);
+        }
+    }
+
+    if bytes_in_use.is_empty() {
+        return Err(malformed("No bytes in use"));
+    }
+
+    let alphabet_size = bytes_in_use.len() + 2;
+    let end_of_block = alphabet_size as u16 - 1;
+
+    let table_count = reader.read_bits(3)? as usize;
+    let selector_count = reader.read_bits(15)? as usize;
+
+    if !(MIN_TABLES..=MAX_TABLES).contains(&table_count) || selector_count == 0 {
+        return Err(malformed("Invalid number of Huffman tables"));
+    }
+
+    let selectors = read_selectors(reader, selector_count, table_count)?;
+    let mut tables = Vec::with_capacity(table_count);
+
+    for _ in 0..table_count {
+        let mut lengths = Vec::with_capacity(alphabet_size);
+        let mut current = reader.read_bits(5)?;
+
+        for _ in 0..alphabet_size {
+            loop {
+                if !(1..=MAX_CODE_LENGTH).contains(&current) {
+                    return Err(malformed("Invalid Huffman code length"));
+                }
+
+                if !reader.read_bit()? {
+                    break;
+                }
+
+                if reader.read_bit()? {
+                    current -= 1;
+                } else {
+                    current += 1;
+                }
+            }
+
+            lengths.push(current as u8);
+        }
+
+        tables.push(CanonicalCode::from_lengths(lengths)?);
+    }
+
+    // Undo the Huffman and move-to-front coding
+    let mut last_column = Vec::new();
+    let mut order = bytes_in_use;
+    let mut run = 0;
+    let mut run_digit = 0;
+
+    for group in 0.. {
+        let selector = *selectors
+            .get(group)
+            .ok_or_else(|| malformed("Missing Huffman table selector"))?;
+        let table = &tables[selector as usize];
+
+        for _ in 0..GROUP_SIZE {
+            let symbol = table.decode_symbol(reader)?;
+
+            if symbol == RUNA || symbol == RUNB {
+                if run_digit == MAX_RUN_DIGITS {
+                    return Err(malformed("Invalid zero run"));
+                }
+
+                run += (symbol as usize + 1) << run_digit;
+                run_digit += 1;
+                continue;
+            }
+
+            if run > block_size - last_column.len() {
+                return Err(malformed("Block too long"));
+            }
+
+            last_column.extend(std::iter::repeat_n(order[0], run));
+            run = 0;
+            run_digit = 0;
+
+            if symbol == end_of_block {
+                return finish_block(last_column, original_index, expected, block, output);
+            }
+
+            if last_column.len() == block_size {
+                return Err(malformed("Block too long"));
+            }
+
+            let position = symbol as usize - 1;
+            order[..=position].rotate_right(1);
+            last_column.push(order[0]);
+        }
+    }
+
+    unreachable!()
+}
+
+/// Inverts the BWT and the initial run-length coding of a block, and checks
+/// its CRC.
+fn finish_block(
+    last_column: Vec<u8>,
+    original_index: usize,
+    expected: u32,
+    block: usize,
+    output: &mut Vec<u8>,
+) -> io::Result<u32> {
+    if original_index >= last_column.len() {
+        return Err(malformed("Invalid BWT index"));
+    }
+
+    let bytes = ibwt(&last_column, original_index);
+
+    let mut crc = Crc::new();
+    let mut previous = None;
+    let mut repeats = 0;
+
+    let mut iter = bytes.into_iter();
+
+    while let Some(byte) = iter.next() {
+        output.push(byte);
+        crc.update(byte);
+
+        repeats = if previous == Some(byte) {
+            repeats + 1
+        } else {
+            1
+        };
+        previous = Some(byte);
+
+        // Four equal bytes are followed by the number of further repeats
+        if repeats == 4 {
+            for _ in 0..iter.next().unwrap_or(0) {
+                output.push(byte);
+                crc.update(byte);
+            }
+
+            previous = None;
+        }
+    }
+
+    let actual = crc.finish();
+
+    if actual != expected {
+        return Err(CorruptionError::BlockChecksum {
+            block,
+            expected,
+            actual,
+        }
+        .into());
+    }
+
+    Ok(actual)
+}
@@ -0,0 +1,257 @@
+use std::{fmt, str::FromStr};
+
+/// Checksum algorithm used to protect the uncompressed data.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Checksum {
+    None,
+    #[default]
+    Crc32,
+    XxHash32,
+}
+
+impl Checksum {
+    pub fn id(self) -> u8 {
+        match self {
+            Checksum::None => 0,
+            Checksum::Crc32 => 1,
+            Checksum::XxHash32 => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Checksum::None),
+            1 => Some(Checksum::Crc32),
+            2 => Some(Checksum::XxHash32),
+            _ => None,
+        }
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            Checksum::None => Hasher::None,
+            Checksum::Crc32 => Hasher::Crc32(Crc32::new()),
+            Checksum::XxHash32 => Hasher::XxHash32(XxHash32::new(0)),
+        }
+    }
+
+    /// Returns the checksum of `bytes`, or `None` when checksums are disabled.
+    pub fn compute(self, bytes: &[u8]) -> Option<u32> {
+        let mut hasher = self.hasher();
+        hasher.update(bytes);
+        hasher.finish()
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Checksum::None),
+            "crc32" => Ok(Checksum::Crc32),
+            "xxhash" => Ok(Checksum::XxHash32),
+            _ => Err(format!(
+                "Unknown checksum {s}, expected none, crc32 or xxhash"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Checksum::None => write!(f, "none"),
+            Checksum::Crc32 => write!(f, "crc32"),
+            Checksum::XxHash32 => write!(f, "xxhash"),
+        }
+    }
+}
+
+/// Incremental checksum computation.
+pub enum Hasher {
+    None,
+    Crc32(Crc32),
+    XxHash32(XxHash32),
+}
+
+impl Hasher {
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::None => {}
+            Hasher::Crc32(crc) => crc.update(bytes),
+            Hasher::XxHash32(xxh) => xxh.update(bytes),
+        }
+    }
+
+    pub fn finish(&self) -> Option<u32> {
+        match self {
+            Hasher::None => None,
+            Hasher::Crc32(crc) => Some(crc.finish()),
+            Hasher::XxHash32(xxh) => Some(xxh.finish()),
+        }
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+};
+
+/// CRC-32 (IEEE 802.3), the checksum used by gzip and zip.
+#[derive(Clone, Default)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32 { crc: 0 }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        let mut crc = !self.crc;
+
+        for &byte in bytes {
+            crc = CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+        }
+
+        self.crc = !crc;
+    }
+
+    pub fn finish(&self) -> u32 {
+        self.crc
+    }
+}
+
+const PRIME32_1: u32 = 0x9E3779B1;
+const PRIME32_2: u32 = 0x85EBCA77;
+const PRIME32_3: u32 = 0xC2B2AE3D;
+const PRIME32_4: u32 = 0x27D4EB2F;
+const PRIME32_5: u32 = 0x165667B1;
+
+/// 32-bit xxHash.
+#[derive(Clone)]
+pub struct XxHash32 {
+    seed: u32,
+    lanes: [u32; 4],
+    buffer: [u8; 16],
+    buffered: usize,
+    total_len: u64,
+}
+
+impl XxHash32 {
+    pub fn new(seed: u32) -> Self {
+        XxHash32 {
+            seed,
+            lanes: [
+                seed.wrapping_add(PRIME32_1).wrapping_add(PRIME32_2),
+                seed.wrapping_add(PRIME32_2),
+                seed,
+                seed.wrapping_sub(PRIME32_1),
+            ],
+            buffer: [0; 16],
+            buffered: 0,
+            total_len: 0,
+        }
+    }
+
+    fn round(acc: u32, lane: u32) -> u32 {
+        acc.wrapping_add(lane.wrapping_mul(PRIME32_2))
+            .rotate_left(13)
+            .wrapping_mul(PRIME32_1)
+    }
+
+    fn stripe(&mut self, stripe: &[u8]) {
+        for (acc, lane) in self.lanes.iter_mut().zip(stripe.chunks_exact(4)) {
+            *acc = Self::round(*acc, u32::from_le_bytes(lane.try_into().unwrap()));
+        }
+    }
+
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        self.total_len += bytes.len() as u64;
+
+        if self.buffered > 0 {
+            let take = (16 - self.buffered).min(bytes.len());
+            self.buffer[self.buffered..self.buffered + take].copy_from_slice(&bytes[..take]);
+            self.buffered += take;
+            bytes = &bytes[take..];
+
+            if self.buffered < 16 {
+                return;
+            }
+
+            let buffer = self.buffer;
+            self.stripe(&buffer);
+            self.buffered = 0;
+        }
+
+        let mut stripes = bytes.chunks_exact(16);
+
+        for stripe in &mut stripes {
+            self.stripe(stripe);
+        }
+
+        let rest = stripes.remainder();
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    pub fn finish(&self) -> u32 {
+        let mut hash = if self.total_len >= 16 {
+            let [v1, v2, v3, v4] = self.lanes;
+            v1.rotate_left(1)
+                .wrapping_add(v2.rotate_left(7))
+                .wrapping_add(v3.rotate_left(12))
+                .wrapping_add(v4.rotate_left(18))
+        } else {
+            self.seed.wrapping_add(PRIME32_5)
+        };
+
+        hash = hash.wrapping_add(self.total_len as u32);
+
+        let mut words = self.buffer[..self.buffered].chunks_exact(4);
+
+        for word in &mut words {
+            let word = u32::from_le_bytes(word.try_into().unwrap());
+            hash = hash
+                .wrapping_add(word.wrapping_mul(PRIME32_3))
+                .rotate_left(17)
+                .wrapping_mul(PRIME32_4);
+        }
+
+        for &byte in words.remainder() {
+            hash = hash
+                .wrapping_add((byte as u32).wrapping_mul(PRIME32_5))
+                .rotate_left(11)
+                .wrapping_mul(PRIME32_1);
+        }
+
+        hash ^= hash >> 15;
+        hash = hash.wrapping_mul(PRIME32_2);
+        hash ^= hash >> 13;
+        hash = hash.wrapping_mul(PRIME32_3);
+        hash ^= hash >> 16;
+
+        hash
+    }
+}
@@ -0,0 +1,168 @@
+use std::io;
+
+use crate::{
+    adaptive_huffman::AdaptiveHuffmanCoder,
+    bwt_coder::{BWTCoder, RunEncoding},
+    bwt_huffman::BWTHuffmanCoder,
+    bwt_mtf_rle_huffman::BwtMtfRleHuffmanCoder,
+    container::BLOCK_SIZE,
+    huffman::HuffmanCoder,
+    markov_arithmetic::MarkovArithmeticCoder,
+    multi_table::MAX_TABLES,
+    rans::ANSCoder,
+    rans_lib::AnsLibraryCoder,
+    rans_order1::ANSOrder1Coder,
+};
+
+/// A compression algorithm that turns a byte slice into a compressed byte
+/// vector and back. Codecs are shared between the threads that compress
+/// blocks in parallel.
+pub trait Codec: Send + Sync {
+    /// Name used to select the codec, e.g. on the command line.
+    fn name(&self) -> &'static str;
+
+    /// Stable numeric identifier of the codec.
+    fn id(&self) -> u8;
+
+    fn encode(&self, bytes: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Decodes `bytes`, failing before it allocates or decodes more than
+    /// `max_len` bytes. The container passes the size it recorded for the
+    /// block, which bounds a corrupted length in `bytes`.
+    fn decode(&self, bytes: &[u8], max_len: usize) -> io::Result<Vec<u8>>;
+}
+
+/// Settings that trade compression ratio for speed and memory.
+///
+/// Decoders never need them: everything they change is recorded in the
+/// compressed data, so a codec found with [`by_id`] decodes the output of any
+/// level.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CompressionOptions {
+    block_size: usize,
+    max_tables: usize,
+    order1: bool,
+}
+
+impl CompressionOptions {
+    pub const MIN_LEVEL: u32 = 1;
+    pub const MAX_LEVEL: u32 = 9;
+
+    /// Options of the highest level.
+    pub fn new() -> Self {
+        Self::from_level(Self::MAX_LEVEL)
+    }
+
+    /// Options for `level`, from 1 (fastest, least memory) to 9 (best
+    /// ratio). The block size halves with every level below 9, from
+    /// [`BLOCK_SIZE`] down to 32 KiB, multi-table coders use between two
+    /// and six tables, and levels below 4 use order-0 models. Only
+    /// markov-huffman and bwt-huffman can use either order; the other codecs
+    /// record a fixed order in their format and take the block size alone.
+    ///
+    /// Panics unless `level` is between [`MIN_LEVEL`] and [`MAX_LEVEL`].
+    ///
+    /// [`BLOCK_SIZE`]: crate::container::BLOCK_SIZE
+    /// [`MIN_LEVEL`]: Self::MIN_LEVEL
+    /// [`MAX_LEVEL`]: Self::MAX_LEVEL
+    pub fn from_level(level: u32) -> Self {
+        assert!((Self::MIN_LEVEL..=Self::MAX_LEVEL).contains(&level));
+
+        CompressionOptions {
+            block_size: BLOCK_SIZE >> (Self::MAX_LEVEL - level),
+            max_tables: 2 + (level as usize - 1) / 2,
+            order1: level >= 4,
+        }
+    }
+
+    /// Panics unless `block_size` is between 1 and [`BLOCK_SIZE`].
+    ///
+    /// [`BLOCK_SIZE`]: crate::container::BLOCK_SIZE
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        assert!((1..=BLOCK_SIZE).contains(&block_size));
+        self.block_size = block_size;
+        self
+    }
+
+    /// Panics unless `max_tables` is between 2 and [`MAX_TABLES`].
+    ///
+    /// [`MAX_TABLES`]: crate::multi_table::MAX_TABLES
+    pub fn with_max_tables(mut self, max_tables: usize) -> Self {
+        assert!((2..=MAX_TABLES).contains(&max_tables));
+        self.max_tables = max_tables;
+        self
+    }
+
+    /// Uses a model per previous byte where a codec supports both orders.
+    pub fn with_order1(mut self, order1: bool) -> Self {
+        self.order1 = order1;
+        self
+    }
+
+    /// Largest block the input is split into, both by the container and by
+    /// the BWT coders.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Most Huffman tables a multi-table coder may use.
+    pub fn max_tables(&self) -> usize {
+        self.max_tables
+    }
+
+    /// Whether models condition on the previous byte.
+    pub fn order1(&self) -> bool {
+        self.order1
+    }
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns one instance of every codec in the crate.
+pub fn codecs() -> Vec<Box<dyn Codec>> {
+    codecs_with_options(&CompressionOptions::new())
+}
+
+/// Returns one instance of every codec in the crate, set up to encode with
+/// `options`.
+pub fn codecs_with_options(options: &CompressionOptions) -> Vec<Box<dyn Codec>> {
+    vec![
+        Box::new(HuffmanCoder::new().with_options(options)),
+        Box::new(
+            BWTCoder::new()
+                .with_run_encoding(RunEncoding::ZeroRuns)
+                .with_options(options),
+        ),
+        Box::new(BWTHuffmanCoder::new().with_options(options)),
+        Box::new(MarkovArithmeticCoder::new()),
+        Box::new(BwtMtfRleHuffmanCoder::new().with_options(options)),
+        Box::new(
+            BwtMtfRleHuffmanCoder::new()
+                .with_multi_table(true)
+                .with_options(options),
+        ),
+        Box::new(ANSCoder::new()),
+        Box::new(AnsLibraryCoder::new()),
+        Box::new(ANSOrder1Coder::new()),
+        Box::new(AdaptiveHuffmanCoder::new()),
+        Box::new(AdaptiveHuffmanCoder::new().with_order1(true)),
+    ]
+}
+
+pub fn by_name(name: &str) -> Option<Box<dyn Codec>> {
+    by_name_with_options(name, &CompressionOptions::new())
+}
+
+pub fn by_name_with_options(name: &str, options: &CompressionOptions) -> Option<Box<dyn Codec>> {
+    codecs_with_options(options)
+        .into_iter()
+        .find(|codec| codec.name() == name)
+}
+
+pub fn by_id(id: u8) -> Option<Box<dyn Codec>> {
+    codecs().into_iter().find(|codec| codec.id() == id)
+}
@@ -0,0 +1,380 @@
+//! Self-describing container around the output of a [`Codec`].
+//!
+//! Layout (integers are big-endian):
+//!
+//! | field         | size | description                                  |
+//! |---------------|------|----------------------------------------------|
+//! | magic         | 4    | `b"MHUF"`                                    |
+//! | version       | 1    | [`VERSION`]                                  |
+//! | algorithm     | 1    | [`Codec::id`] of the codec used              |
+//! | flags         | 1    | bits 0-1: [`Checksum::id`], bit 2: index     |
+//! | original size | 8    | uncompressed length, or [`UNKNOWN_SIZE`]     |
+//! | blocks        | ...  | see below                                    |
+//! | end marker    | 4    | zero                                         |
+//! | checksum      | 0/4  | checksum of the whole uncompressed data      |
+//! | index         | ...  | if flagged, see below                        |
+//!
+//! The input is split into blocks of at most [`BLOCK_SIZE`] bytes, and every
+//! block is encoded independently:
+//!
+//! | field           | size | description                          |
+//! |-----------------|------|--------------------------------------|
+//! | original size   | 4    | length of the uncompressed block     |
+//! | compressed size | 4    | length of the payload                |
+//! | payload         | ...  | output of [`Codec::encode`]          |
+//! | checksum        | 0/4  | checksum of the uncompressed block   |
+//!
+//! The index locates every block, so that blocks can be handed to several
+//! threads or read out of order:
+//!
+//! | field   | size | description                                          |
+//! |---------|------|------------------------------------------------------|
+//! | entries | 16   | per block: offset, size and original size, see below |
+//! | count   | 4    | number of blocks                                     |
+//! | magic   | 4    | `b"MHIX"`                                            |
+//!
+//! An entry holds the offset of the block from the start of the stream (8
+//! bytes), its length in the stream including sizes and checksum (4 bytes)
+//! and its uncompressed length (4 bytes).
+
+use std::io::{self, Read, Write};
+
+use crate::{
+    checksum::Checksum,
+    codec::{self, Codec},
+    error::{CorruptionError, malformed},
+    stream::Decoder,
+};
+
+pub const MAGIC: [u8; 4] = *b"MHUF";
+pub const INDEX_MAGIC: [u8; 4] = *b"MHIX";
+pub const VERSION: u8 = 1;
+
+/// Largest block the container holds. Matches the BWT chunk size, so a block
+/// is never split further by the BWT coders.
+pub const BLOCK_SIZE: usize = crate::bwt_coder::CHUNK_SIZE;
+
+/// Original size recorded by writers that do not know the input length.
+pub const UNKNOWN_SIZE: u64 = u64::MAX;
+
+const FLAG_CHECKSUM_MASK: u8 = 0b11;
+const FLAG_INDEX: u8 = 0b100;
+
+/// Length of the header in the stream.
+pub const HEADER_SIZE: u64 = 15;
+
+pub(crate) const INDEX_ENTRY_SIZE: usize = 16;
+
+pub struct Header {
+    pub version: u8,
+    pub algorithm: u8,
+    pub flags: u8,
+    pub original_size: u64,
+}
+
+impl Header {
+    pub fn new(codec: &dyn Codec, checksum: Checksum, original_size: u64) -> Self {
+        Header {
+            version: VERSION,
+            algorithm: codec.id(),
+            flags: checksum.id() | FLAG_INDEX,
+            original_size,
+        }
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&[self.version, self.algorithm, self.flags])?;
+        writer.write_all(&self.original_size.to_be_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        read_exact(reader, &mut magic)?;
+
+        if magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a compressed file",
+            ));
+        }
+
+        let mut fields = [0u8; 3];
+        read_exact(reader, &mut fields)?;
+        let [version, algorithm, flags] = fields;
+
+        if version != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported format version {version}"),
+            ));
+        }
+
+        let mut size_bytes = [0u8; 8];
+        read_exact(reader, &mut size_bytes)?;
+        let original_size = u64::from_be_bytes(size_bytes);
+
+        Ok(Header {
+            version,
+            algorithm,
+            flags,
+            original_size,
+        })
+    }
+
+    /// Looks up the codec that produced the payload.
+    pub fn codec(&self) -> io::Result<Box<dyn Codec>> {
+        codec::by_id(self.algorithm).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown algorithm id {}", self.algorithm),
+            )
+        })
+    }
+
+    /// Whether the stream ends with a block index.
+    pub fn has_index(&self) -> bool {
+        self.flags & FLAG_INDEX != 0
+    }
+
+    pub fn checksum(&self) -> io::Result<Checksum> {
+        Checksum::from_id(self.flags & FLAG_CHECKSUM_MASK)
+            .ok_or_else(|| malformed("unknown checksum type"))
+    }
+}
+
+pub fn compress(codec: &dyn Codec, bytes: &[u8], checksum: Checksum) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+
+    Header::new(codec, checksum, bytes.len() as u64).write(&mut output)?;
+
+    let mut index = Vec::new();
+
+    for block in bytes.chunks(BLOCK_SIZE) {
+        let encoded = encode_block(codec, checksum, block)?;
+        index.push(BlockEntry::new(output.len() as u64, &encoded, block.len()));
+        output.extend_from_slice(&encoded);
+    }
+
+    write_end(&mut output, checksum.compute(bytes))?;
+    write_index(&mut output, &index)?;
+
+    Ok(output)
+}
+
+pub fn decompress(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    Decoder::new(bytes)?.read_to_end(&mut output)?;
+    Ok(output)
+}
+
+/// Returns a block as it is written to the stream, so that blocks can be
+/// encoded in parallel and written in order.
+pub(crate) fn encode_block(
+    codec: &dyn Codec,
+    checksum: Checksum,
+    block: &[u8],
+) -> io::Result<Vec<u8>> {
+    let payload = codec.encode(block)?;
+    let mut output = Vec::with_capacity(payload.len() + 12);
+
+    output.extend_from_slice(&(block.len() as u32).to_be_bytes());
+    output.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    output.extend_from_slice(&payload);
+
+    if let Some(sum) = checksum.compute(block) {
+        output.extend_from_slice(&sum.to_be_bytes());
+    }
+
+    Ok(output)
+}
+
+pub(crate) fn write_end<W: Write>(writer: &mut W, content_checksum: Option<u32>) -> io::Result<()> {
+    writer.write_all(&0u32.to_be_bytes())?;
+
+    if let Some(sum) = content_checksum {
+        writer.write_all(&sum.to_be_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// A block as read from the stream, not decoded yet.
+pub(crate) struct Frame {
+    original_size: usize,
+    payload: Vec<u8>,
+    checksum: Option<u32>,
+}
+
+impl Frame {
+    /// Length of the block in the stream.
+    pub(crate) fn len(&self) -> u64 {
+        8 + self.payload.len() as u64 + if self.checksum.is_some() { 4 } else { 0 }
+    }
+
+    pub(crate) fn original_size(&self) -> usize {
+        self.original_size
+    }
+}
+
+/// Reads the next block without decoding it, or returns `None` at the end
+/// marker.
+pub(crate) fn read_frame<R: Read>(reader: &mut R, checksum: Checksum) -> io::Result<Option<Frame>> {
+    let original_size = read_u32(reader)? as usize;
+
+    if original_size == 0 {
+        return Ok(None);
+    }
+
+    if original_size > BLOCK_SIZE {
+        return Err(malformed("invalid block size"));
+    }
+
+    let compressed_size = read_u32(reader)? as u64;
+
+    let mut payload = Vec::new();
+    reader
+        .by_ref()
+        .take(compressed_size)
+        .read_to_end(&mut payload)?;
+
+    if payload.len() as u64 != compressed_size {
+        return Err(malformed("truncated block"));
+    }
+
+    let checksum = match checksum {
+        Checksum::None => None,
+        _ => Some(read_u32(reader)?),
+    };
+
+    Ok(Some(Frame {
+        original_size,
+        payload,
+        checksum,
+    }))
+}
+
+/// Decodes a block read by [`read_frame`] and checks it; `index` is the
+/// number of the block, for errors.
+pub(crate) fn decode_frame(
+    frame: &Frame,
+    codec: &dyn Codec,
+    checksum: Checksum,
+    index: usize,
+) -> io::Result<Vec<u8>> {
+    // Codecs decode from memory, so whatever they fail on is the payload
+    let data = codec
+        .decode(&frame.payload, frame.original_size)
+        .map_err(|e| match CorruptionError::find(&e) {
+            Some(_) => e,
+            None if e.kind() == io::ErrorKind::UnexpectedEof => malformed("block data ends early"),
+            None => malformed("invalid block data"),
+        })?;
+
+    if data.len() != frame.original_size {
+        return Err(CorruptionError::SizeMismatch {
+            expected: frame.original_size as u64,
+            actual: data.len() as u64,
+        }
+        .into());
+    }
+
+    if let (Some(expected), Some(actual)) = (frame.checksum, checksum.compute(&data))
+        && expected != actual
+    {
+        return Err(CorruptionError::BlockChecksum {
+            block: index,
+            expected,
+            actual,
+        }
+        .into());
+    }
+
+    Ok(data)
+}
+
+/// Reads the content checksum after the end marker and compares it.
+pub(crate) fn read_end<R: Read>(reader: &mut R, content_checksum: Option<u32>) -> io::Result<()> {
+    if let Some(actual) = content_checksum {
+        let expected = read_u32(reader)?;
+
+        if expected != actual {
+            return Err(CorruptionError::ContentChecksum { expected, actual }.into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Location of a block in the stream, as recorded in the index.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BlockEntry {
+    /// Offset of the block from the start of the stream.
+    pub offset: u64,
+    /// Length of the block in the stream, including its sizes and checksum.
+    pub size: u32,
+    /// Length of the uncompressed block.
+    pub original_size: u32,
+}
+
+impl BlockEntry {
+    pub(crate) fn new(offset: u64, encoded: &[u8], original_size: usize) -> Self {
+        BlockEntry {
+            offset,
+            size: encoded.len() as u32,
+            original_size: original_size as u32,
+        }
+    }
+}
+
+pub(crate) fn write_index<W: Write>(writer: &mut W, index: &[BlockEntry]) -> io::Result<()> {
+    for entry in index {
+        writer.write_all(&entry.offset.to_be_bytes())?;
+        writer.write_all(&entry.size.to_be_bytes())?;
+        writer.write_all(&entry.original_size.to_be_bytes())?;
+    }
+
+    writer.write_all(&(index.len() as u32).to_be_bytes())?;
+    writer.write_all(&INDEX_MAGIC)
+}
+
+/// Reads an index of `count` blocks, as found after the end marker.
+pub(crate) fn read_index<R: Read>(reader: &mut R, count: usize) -> io::Result<Vec<BlockEntry>> {
+    let mut index = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        let mut entry = [0u8; INDEX_ENTRY_SIZE];
+        read_exact(reader, &mut entry)?;
+
+        index.push(BlockEntry {
+            offset: u64::from_be_bytes(entry[..8].try_into().unwrap()),
+            size: u32::from_be_bytes(entry[8..12].try_into().unwrap()),
+            original_size: u32::from_be_bytes(entry[12..].try_into().unwrap()),
+        });
+    }
+
+    let stored_count = read_u32(reader)? as usize;
+    let mut magic = [0u8; 4];
+    read_exact(reader, &mut magic)?;
+
+    if stored_count != count || magic != INDEX_MAGIC {
+        return Err(malformed("invalid block index"));
+    }
+
+    Ok(index)
+}
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => malformed("truncated input"),
+        _ => e,
+    })
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    read_exact(reader, &mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
@@ -0,0 +1,65 @@
+use std::{error::Error, fmt, io};
+
+/// Compressed data that failed validation while decoding.
+///
+/// Decoders return it wrapped in an [`io::Error`] of kind
+/// [`io::ErrorKind::InvalidData`]; use [`CorruptionError::find`] to get it back.
+#[derive(Debug)]
+pub enum CorruptionError {
+    /// The checksum of a decoded block does not match the stored one.
+    BlockChecksum {
+        block: usize,
+        expected: u32,
+        actual: u32,
+    },
+    /// The checksum of the whole decoded data does not match the stored one.
+    ContentChecksum { expected: u32, actual: u32 },
+    /// The decoded data is not as long as the stream says it should be.
+    SizeMismatch { expected: u64, actual: u64 },
+    /// The stream is structurally invalid.
+    Malformed(&'static str),
+}
+
+impl CorruptionError {
+    /// Returns the corruption error carried by `error`, if any.
+    pub fn find(error: &io::Error) -> Option<&CorruptionError> {
+        error.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for CorruptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CorruptionError::BlockChecksum {
+                block,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Checksum mismatch in block {block}: expected {expected:08x}, got {actual:08x}"
+            ),
+            CorruptionError::ContentChecksum { expected, actual } => write!(
+                f,
+                "Checksum mismatch: expected {expected:08x}, got {actual:08x}"
+            ),
+            CorruptionError::SizeMismatch { expected, actual } => write!(
+                f,
+                "Decompressed size mismatch: expected {expected} bytes, got {actual}"
+            ),
+            CorruptionError::Malformed(reason) => write!(f, "Corrupted input: {reason}"),
+        }
+    }
+}
+
+impl Error for CorruptionError {}
+
+impl From<CorruptionError> for io::Error {
+    fn from(error: CorruptionError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// Shorthand for a [`CorruptionError::Malformed`] wrapped in an [`io::Error`].
+pub fn malformed(reason: &'static str) -> io::Error {
+    CorruptionError::Malformed(reason).into()
+}
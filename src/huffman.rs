@@ -1,16 +1,22 @@
-#![allow(unused)]
-
 use std::collections::VecDeque;
 use std::io;
 use std::io::Cursor;
-use std::io::Read;
 use std::io::Write;
-use std::marker::PhantomData;
-use std::process::Output;
 
-use bitbit::BitReader;
 use bitbit::BitWriter;
-use bitbit::MSB;
+
+use crate::{
+    bit_reader::BitReader,
+    codec::{Codec, CompressionOptions},
+    error::malformed,
+    varint,
+};
+
+/// Longest code the bit writer can emit in one call.
+pub const MAX_CODE_LENGTH: usize = 32;
+pub const DEFAULT_MAX_CODE_LENGTH: usize = 15;
+/// Most symbols a code can have, enough for the alphabet of bzip2.
+pub const MAX_ALPHABET_SIZE: usize = 258;
 
 pub struct TreeNode {
     frequency: u64,
@@ -19,7 +25,7 @@ pub struct TreeNode {
 
 pub enum TreeNodeKind {
     Leaf {
-        byte: u8,
+        symbol: u16,
     },
     Node {
         left: Box<TreeNode>,
@@ -29,16 +35,55 @@ pub enum TreeNodeKind {
 
 #[derive(Clone, Copy, Default)]
 pub struct Code {
-    word: u64,
-    len: usize,
+    pub(crate) word: u64,
+    pub(crate) len: usize,
 }
 
 impl TreeNode {
-    pub fn build(frequencies: &[u64; 256]) -> Option<Self> {
+    /// Builds a Huffman tree over the symbols with a nonzero frequency, or
+    /// returns `None` if there are none. Trees deeper than `max_length` are
+    /// rebuilt from code lengths limited with package-merge.
+    ///
+    /// Panics unless `max_length` is between 8 and [`MAX_CODE_LENGTH`] and
+    /// leaves room for a code per symbol, or if there are more than
+    /// [`MAX_ALPHABET_SIZE`] frequencies.
+    pub fn build(frequencies: &[u64], max_length: usize) -> Option<Self> {
+        assert!((8..=MAX_CODE_LENGTH).contains(&max_length));
+        assert!(frequencies.len() <= MAX_ALPHABET_SIZE);
+
+        let present = frequencies
+            .iter()
+            .filter(|&&frequency| frequency > 0)
+            .count();
+        assert!(
+            present <= 1 << max_length,
+            "too many symbols for {max_length}-bit codes"
+        );
+
+        let tree = Self::build_unlimited(frequencies)?;
+
+        if tree.depth() <= max_length {
+            return Some(tree);
+        }
+
+        let lengths = package_merge(frequencies, max_length);
+        let mut leaves: Vec<(u8, u16)> = (0..lengths.len() as u16)
+            .filter(|&symbol| lengths[symbol as usize] > 0)
+            .map(|symbol| (lengths[symbol as usize], symbol))
+            .collect();
+        leaves.sort();
+
+        Some(Self::from_leaves(&leaves, 0, frequencies))
+    }
+
+    fn build_unlimited(frequencies: &[u64]) -> Option<Self> {
         let mut queue = VecDeque::<TreeNode>::new();
 
-        for byte in 0..=255 {
-            let frequency = frequencies[byte as usize];
+        for (symbol, &frequency) in frequencies.iter().enumerate() {
+            if frequency == 0 {
+                continue;
+            }
+
             let index = queue
                 .binary_search_by_key(&frequency, |node| node.frequency)
                 .unwrap_or_else(|idx| idx);
@@ -47,7 +92,9 @@ impl TreeNode {
                 index,
                 TreeNode {
                     frequency,
-                    kind: TreeNodeKind::Leaf { byte },
+                    kind: TreeNodeKind::Leaf {
+                        symbol: symbol as u16,
+                    },
                 },
             );
         }
@@ -77,167 +124,1134 @@ impl TreeNode {
         queue.pop_front()
     }
 
-    pub fn codes(&self) -> [Code; 256] {
-        fn codes_recursive(node: &TreeNode, codes: &mut [Code; 256], word: u64, len: usize) {
+    fn depth(&self) -> usize {
+        match &self.kind {
+            TreeNodeKind::Leaf { .. } => 0,
+            TreeNodeKind::Node { left, right } => 1 + left.depth().max(right.depth()),
+        }
+    }
+
+    /// Builds the subtree at `depth` holding `leaves`, which are sorted by
+    /// code length and form a complete code below that depth.
+    fn from_leaves(leaves: &[(u8, u16)], depth: usize, frequencies: &[u64]) -> Self {
+        if let [(_, symbol)] = *leaves {
+            return TreeNode {
+                frequency: frequencies[symbol as usize],
+                kind: TreeNodeKind::Leaf { symbol },
+            };
+        }
+
+        // The left half takes the shortest codes until it is full, measured
+        // in units of the longest code
+        let longest = leaves.last().unwrap().0 as usize;
+        let half = 1u64 << (longest - depth - 1);
+        let mut filled = 0;
+        let mut split = 0;
+
+        while filled < half {
+            filled += 1 << (longest - leaves[split].0 as usize);
+            split += 1;
+        }
+
+        let left = Self::from_leaves(&leaves[..split], depth + 1, frequencies);
+        let right = Self::from_leaves(&leaves[split..], depth + 1, frequencies);
+
+        TreeNode {
+            frequency: left.frequency + right.frequency,
+            kind: TreeNodeKind::Node {
+                left: Box::new(left),
+                right: Box::new(right),
+            },
+        }
+    }
+
+    /// Returns the depth of every leaf, and 0 for the other symbols of an
+    /// alphabet of `alphabet_size`. A lone leaf gets a length of 1 so that it
+    /// still shows up as present.
+    pub fn code_lengths(&self, alphabet_size: usize) -> Vec<u8> {
+        fn lengths_recursive(node: &TreeNode, lengths: &mut [u8], depth: u8) {
             match &node.kind {
-                TreeNodeKind::Leaf { byte } => codes[*byte as usize] = Code { word, len },
+                TreeNodeKind::Leaf { symbol } => lengths[*symbol as usize] = depth.max(1),
                 TreeNodeKind::Node { left, right } => {
-                    codes_recursive(&left, codes, word << 1, len + 1);
-                    codes_recursive(&right, codes, (word << 1) | 1, len + 1);
+                    lengths_recursive(left, lengths, depth + 1);
+                    lengths_recursive(right, lengths, depth + 1);
                 }
             }
         }
 
-        let mut codes = [Code::default(); 256];
-        codes_recursive(self, &mut codes, 0, 0);
-        codes
+        let mut lengths = vec![0u8; alphabet_size];
+        lengths_recursive(self, &mut lengths, 0);
+        lengths
     }
+}
 
-    pub fn encode<W: Write>(&self, writer: &mut BitWriter<W>) -> io::Result<()> {
-        match &self.kind {
-            TreeNodeKind::Leaf { byte } => {
-                writer.write_bit(true)?;
-                writer.write_byte(*byte)?;
+/// Computes optimal code lengths of at most `max_length` bits with the
+/// package-merge algorithm.
+///
+/// Starting from the leaves sorted by frequency, each round pairs up the
+/// items of the previous round into packages and merges them with the
+/// leaves again. Of the final list the `2n - 2` cheapest items are taken,
+/// and every leaf gains one bit of length for each list it is taken from.
+fn package_merge(frequencies: &[u64], max_length: usize) -> Vec<u8> {
+    let mut leaves: Vec<(u64, usize)> = frequencies
+        .iter()
+        .enumerate()
+        .filter(|&(_, &frequency)| frequency > 0)
+        .map(|(symbol, &frequency)| (frequency, symbol))
+        .collect();
+    leaves.sort();
+
+    let mut lengths = vec![0u8; frequencies.len()];
+
+    if let [(_, symbol)] = leaves[..] {
+        lengths[symbol] = 1;
+        return lengths;
+    }
+
+    // Whether each item of every round's list is a package
+    let mut rounds = vec![vec![false; leaves.len()]];
+    let mut items: Vec<u64> = leaves.iter().map(|&(frequency, _)| frequency).collect();
+
+    for _ in 1..max_length {
+        let packages: Vec<u64> = items
+            .chunks_exact(2)
+            .map(|pair| pair[0] + pair[1])
+            .collect();
+        let mut merged = Vec::with_capacity(leaves.len() + packages.len());
+        let mut is_package = Vec::with_capacity(leaves.len() + packages.len());
+        let (mut leaf, mut package) = (0, 0);
+
+        while leaf < leaves.len() || package < packages.len() {
+            if package == packages.len()
+                || (leaf < leaves.len() && leaves[leaf].0 <= packages[package])
+            {
+                merged.push(leaves[leaf].0);
+                is_package.push(false);
+                leaf += 1;
+            } else {
+                merged.push(packages[package]);
+                is_package.push(true);
+                package += 1;
             }
+        }
+
+        items = merged;
+        rounds.push(is_package);
+    }
+
+    // Walk back from the final list: the packages taken from a list select
+    // twice as many items from the list before it
+    let mut taken = 2 * leaves.len() - 2;
+
+    for is_package in rounds.iter().rev() {
+        let packages = is_package[..taken]
+            .iter()
+            .filter(|&&package| package)
+            .count();
 
-            TreeNodeKind::Node { left, right } => {
-                writer.write_bit(false)?;
-                left.encode(writer)?;
-                right.encode(writer)?;
+        for &(_, symbol) in &leaves[..taken - packages] {
+            lengths[symbol] += 1;
+        }
+
+        taken = 2 * packages;
+    }
+
+    lengths
+}
+
+/// A Huffman code described by its code lengths alone. Codes are assigned in
+/// order of length, and symbols of the same length get consecutive codes in
+/// increasing symbol order, as in DEFLATE.
+///
+/// A code with a single symbol spends no bits on it.
+pub struct CanonicalCode {
+    lengths: Vec<u8>,
+    codes: Vec<Code>,
+    // Decoding table indexed by the first `table_bits` bits, followed by the
+    // tables for the bits after them
+    table: Vec<TableEntry>,
+    table_bits: u32,
+    // Symbols in the order of their codes
+    symbols: Vec<u16>,
+}
+
+/// Number of bits resolved by a single lookup in the decoding table.
+const TABLE_BITS: u32 = 10;
+
+#[derive(Clone, Copy, Default)]
+struct TableEntry {
+    // Symbol, or the position of the next table if `length` is zero
+    value: u32,
+    // Length of the whole code
+    length: u8,
+    // Bits indexing the next table
+    bits: u8,
+}
+
+impl CanonicalCode {
+    /// Builds a code over an alphabet of as many symbols as there are
+    /// `frequencies`, with codes for the ones that occur.
+    pub fn from_frequencies(frequencies: &[u64], max_length: usize) -> Self {
+        let lengths = match TreeNode::build(frequencies, max_length) {
+            Some(tree) => tree.code_lengths(frequencies.len()),
+            None => vec![0; frequencies.len()],
+        };
+
+        Self::new(lengths)
+    }
+
+    /// Checks that `lengths` describe a complete prefix code before building
+    /// it. Only a code with a single symbol may leave codes unused. This keeps
+    /// the decoding tables as small as the code needs.
+    ///
+    /// Panics if there are more than [`MAX_ALPHABET_SIZE`] lengths.
+    pub fn from_lengths(lengths: Vec<u8>) -> io::Result<Self> {
+        assert!(lengths.len() <= MAX_ALPHABET_SIZE);
+
+        let max_length = lengths.iter().copied().max().unwrap_or(0) as usize;
+        let mut counts = vec![0u16; max_length + 1];
+
+        for &length in &lengths {
+            counts[length as usize] += 1;
+        }
+
+        // Number of codes still available at the current length. Once it
+        // exceeds the number of symbols it can no longer run out, so it is
+        // capped to keep it from overflowing.
+        let mut left = 1u32;
+
+        for &count in &counts[1..] {
+            left = (left * 2).min(512);
+
+            if (count as u32) > left {
+                return Err(malformed("Oversubscribed Huffman code"));
             }
+
+            left -= count as u32;
+        }
+
+        if left > 0 && counts[1..].iter().sum::<u16>() > 1 {
+            return Err(malformed("Incomplete Huffman code"));
+        }
+
+        Ok(Self::new(lengths))
+    }
+
+    fn new(lengths: Vec<u8>) -> Self {
+        let max_length = lengths.iter().copied().max().unwrap_or(0) as usize;
+        let mut counts = vec![0u64; max_length + 1];
+
+        for &length in lengths.iter().filter(|&&length| length > 0) {
+            counts[length as usize] += 1;
+        }
+
+        // First code of every length
+        let mut next = vec![0u64; max_length + 1];
+
+        for length in 1..=max_length {
+            next[length] = (next[length - 1] + counts[length - 1]) << 1;
+        }
+
+        let mut codes = vec![Code::default(); lengths.len()];
+
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length > 0 {
+                let len = length as usize;
+                codes[symbol] = Code {
+                    word: next[len],
+                    len,
+                };
+                next[len] += 1;
+            }
+        }
+
+        let mut symbols: Vec<u16> = (0..lengths.len() as u16)
+            .filter(|&s| lengths[s as usize] > 0)
+            .collect();
+        symbols.sort_by_key(|&symbol| lengths[symbol as usize]);
+
+        if let [symbol] = symbols[..] {
+            codes[symbol as usize] = Code::default();
+        }
+
+        let table_bits = (max_length as u32).clamp(1, TABLE_BITS);
+        let mut table = vec![TableEntry::default(); 1 << table_bits];
+
+        if symbols.len() > 1 {
+            let sorted: Vec<(u16, Code)> = symbols
+                .iter()
+                .map(|&symbol| (symbol, codes[symbol as usize]))
+                .collect();
+
+            fill_table(&mut table, 0, table_bits, 0, &sorted);
+        }
+
+        CanonicalCode {
+            lengths,
+            codes,
+            table,
+            table_bits,
+            symbols,
+        }
+    }
+
+    pub fn code(&self, symbol: u16) -> Code {
+        self.codes[symbol as usize]
+    }
+
+    /// Code length of `symbol`, or 0 if it has no code.
+    pub fn length(&self, symbol: u16) -> u8 {
+        self.lengths[symbol as usize]
+    }
+
+    /// Writes a bit telling whether the code has any symbols, then the code
+    /// lengths in symbol order. A `0` bit starts a run of absent symbols,
+    /// followed by the Elias gamma coded run length. A `1` bit marks a
+    /// present symbol, followed by the Elias gamma coded difference to the
+    /// length of the previous present symbol.
+    pub fn write<W: Write>(&self, writer: &mut BitWriter<W>) -> io::Result<()> {
+        writer.write_bit(!self.symbols.is_empty())?;
+
+        for (present, value) in self.length_tokens() {
+            writer.write_bit(present)?;
+            write_gamma(writer, value)?;
         }
 
         Ok(())
     }
 
-    pub fn decode<R: Read>(reader: &mut BitReader<R, MSB>) -> io::Result<Self> {
-        if reader.read_bit()? {
-            let byte = reader.read_byte()?;
+    /// Number of bits [`CanonicalCode::write`] takes.
+    pub fn table_bits(&self) -> u64 {
+        let tokens = self.length_tokens().into_iter();
+        1 + tokens.map(|(_, value)| 2 * gamma_bits(value)).sum::<u64>()
+    }
 
-            Ok(TreeNode {
-                frequency: 0,
-                kind: TreeNodeKind::Leaf { byte },
-            })
-        } else {
-            let left = TreeNode::decode(reader)?;
-            let right = TreeNode::decode(reader)?;
-
-            Ok(TreeNode {
-                frequency: 0,
-                kind: TreeNodeKind::Node {
-                    left: Box::new(left),
-                    right: Box::new(right),
-                },
-            })
+    /// Bits needed to code symbols with `frequencies`, or `None` if one of
+    /// them has no code.
+    pub fn cost(&self, frequencies: &[u64]) -> Option<u64> {
+        let mut bits = 0;
+
+        for (symbol, &frequency) in frequencies.iter().enumerate() {
+            if frequency > 0 {
+                if self.lengths[symbol] == 0 {
+                    return None;
+                }
+
+                bits += frequency * self.codes[symbol].len as u64;
+            }
         }
+
+        Some(bits)
     }
 
-    pub fn decode_symbol<R: Read>(&self, reader: &mut BitReader<R, MSB>) -> io::Result<u8> {
-        let mut node = self;
+    /// Splits the code lengths into runs of absent symbols and present
+    /// symbols, each with the value that is written for it.
+    fn length_tokens(&self) -> Vec<(bool, u32)> {
+        let mut tokens = Vec::new();
 
-        loop {
-            match &node.kind {
-                TreeNodeKind::Leaf { byte } => return Ok(*byte),
-                TreeNodeKind::Node { left, right } => {
-                    if reader.read_bit()? {
-                        node = right;
-                    } else {
-                        node = left;
-                    }
+        if self.symbols.is_empty() {
+            return tokens;
+        }
+
+        let mut previous = 0i32;
+        let mut symbol = 0;
+
+        while symbol < self.lengths.len() {
+            let length = self.lengths[symbol];
+
+            if length == 0 {
+                let run = self.lengths[symbol..]
+                    .iter()
+                    .take_while(|&&length| length == 0)
+                    .count();
+
+                tokens.push((false, run as u32));
+                symbol += run;
+            } else {
+                let delta = length as i32 - previous;
+                let zigzag = ((delta << 1) ^ (delta >> 31)) as u32;
+
+                tokens.push((true, zigzag + 1));
+                previous = length as i32;
+                symbol += 1;
+            }
+        }
+
+        tokens
+    }
+
+    /// Reads a code over an alphabet of `alphabet_size` symbols written by
+    /// [`CanonicalCode::write`].
+    ///
+    /// Panics if `alphabet_size` is larger than [`MAX_ALPHABET_SIZE`].
+    pub fn read(reader: &mut BitReader, alphabet_size: usize) -> io::Result<Self> {
+        let mut lengths = vec![0u8; alphabet_size];
+
+        if !reader.read_bit()? {
+            return Self::from_lengths(lengths);
+        }
+
+        let mut previous = 0i32;
+        let mut symbol = 0;
+
+        while symbol < alphabet_size {
+            if reader.read_bit()? {
+                let zigzag = read_gamma(reader)? - 1;
+                let delta = (zigzag >> 1) as i32 ^ -((zigzag & 1) as i32);
+                let length = previous + delta;
+
+                if !(1..=MAX_CODE_LENGTH as i32).contains(&length) {
+                    return Err(malformed("Invalid Huffman code length"));
+                }
+
+                lengths[symbol] = length as u8;
+                previous = length;
+                symbol += 1;
+            } else {
+                let run = read_gamma(reader)? as usize;
+
+                if symbol + run > alphabet_size {
+                    return Err(malformed("Invalid Huffman code length"));
                 }
+
+                symbol += run;
+            }
+        }
+
+        Self::from_lengths(lengths)
+    }
+
+    pub fn decode_symbol(&self, reader: &mut BitReader) -> io::Result<u16> {
+        let entry = self.table[reader.peek(self.table_bits) as usize];
+
+        if entry.length > 0 {
+            reader.consume(entry.length as u32)?;
+            return Ok(entry.value as u16);
+        }
+
+        self.decode_long_symbol(reader, entry)
+    }
+
+    /// Decodes a code longer than the first table by following `entry` into
+    /// the tables for the bits after it.
+    #[cold]
+    fn decode_long_symbol(&self, reader: &mut BitReader, mut entry: TableEntry) -> io::Result<u16> {
+        if let [symbol] = self.symbols[..] {
+            return Ok(symbol);
+        }
+
+        let mut bits = self.table_bits;
+
+        while entry.length == 0 {
+            if entry.bits == 0 {
+                return Err(malformed("Invalid Huffman code"));
             }
+
+            bits += entry.bits as u32;
+            let index = reader.peek(bits) & ((1 << entry.bits) - 1);
+            entry = self.table[(entry.value + index) as usize];
         }
+
+        reader.consume(entry.length as u32)?;
+        Ok(entry.value as u16)
     }
 }
 
+/// Fills the `bits`-bit table at `start` with `codes`, which share their
+/// first `skip` bits and are sorted by code. Codes that do not fit share an
+/// entry linking to a table appended for the bits after it, of at most
+/// [`TABLE_BITS`] bits.
+fn fill_table(
+    table: &mut Vec<TableEntry>,
+    start: usize,
+    bits: u32,
+    skip: u32,
+    codes: &[(u16, Code)],
+) {
+    let mut i = 0;
+
+    while i < codes.len() {
+        let (symbol, Code { word, len }) = codes[i];
+        let rest = len as u32 - skip;
+
+        if rest <= bits {
+            // The entries of every bit string the code is a prefix of
+            let shift = bits - rest;
+            let index = ((word as usize) & ((1 << rest) - 1)) << shift;
+
+            table[start + index..start + index + (1 << shift)].fill(TableEntry {
+                value: symbol as u32,
+                length: len as u8,
+                bits: 0,
+            });
+
+            i += 1;
+            continue;
+        }
+
+        // Longer codes with the same next `bits` bits are adjacent
+        let prefix = |&(_, code): &(u16, Code)| {
+            (code.word >> (code.len as u32 - skip - bits)) as usize & ((1 << bits) - 1)
+        };
+
+        let index = prefix(&codes[i]);
+        let count = codes[i..]
+            .iter()
+            .take_while(|code| prefix(code) == index)
+            .count();
+        let group = &codes[i..i + count];
+
+        let longest = group[count - 1].1.len as u32 - skip - bits;
+        let next_bits = longest.min(TABLE_BITS);
+        let next = table.len();
+
+        table.resize(next + (1 << next_bits), TableEntry::default());
+        table[start + index] = TableEntry {
+            value: next as u32,
+            length: 0,
+            bits: next_bits as u8,
+        };
+
+        fill_table(table, next, next_bits, skip + bits, group);
+        i += count;
+    }
+}
+
+/// Number of significant bits of `value`; its Elias gamma code is one less
+/// zero bits followed by the value itself.
+fn gamma_bits(value: u32) -> u64 {
+    32 - value.leading_zeros() as u64
+}
+
+fn write_gamma<W: Write>(writer: &mut BitWriter<W>, value: u32) -> io::Result<()> {
+    let bits = gamma_bits(value) as usize;
+
+    for _ in 1..bits {
+        writer.write_bit(false)?;
+    }
+
+    writer.write_bits(value, bits)
+}
+
+fn read_gamma(reader: &mut BitReader) -> io::Result<u32> {
+    let mut bits = 1;
+
+    while !reader.read_bit()? {
+        bits += 1;
+
+        if bits > 32 {
+            return Err(malformed("Invalid Elias gamma code"));
+        }
+    }
+
+    Ok((1 << (bits - 1)) | reader.read_bits(bits - 1)?)
+}
+
+/// Blocks are never split below this size, as their code tables would cost
+/// more than adapting to the data gains.
+const MIN_BLOCK_SIZE: usize = 1 << 15;
+// Split points tried in every block
+const SPLIT_CANDIDATES: usize = 8;
+// Rough cost of describing one symbol in a code table
+const TABLE_BITS_PER_SYMBOL: f64 = 6.0;
+
+/// Huffman coder with a code per previous byte.
+///
+/// The input is split into blocks with codes of their own wherever the
+/// statistics change enough to pay for new code tables.
+///
+/// Layout: LEB128 original length, number of blocks and length of every
+/// block, then the blocks as one bit stream. A block is a bit telling
+/// whether any context has a code of its own and, if so, a 32-byte bitmap of
+/// those contexts, then the code shared by all other contexts, the codes of
+/// the contexts in the bitmap, then the coded data. The context carries over
+/// between blocks.
 pub struct HuffmanCoder {
-    p: PhantomData<()>,
+    max_code_length: usize,
+    order1: bool,
 }
 
 impl HuffmanCoder {
     pub fn new() -> Self {
-        HuffmanCoder { p: PhantomData }
+        HuffmanCoder {
+            max_code_length: DEFAULT_MAX_CODE_LENGTH,
+            order1: true,
+        }
     }
 
-    pub fn encode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
-        let mut output = Vec::new();
-        let mut output_cursor = Cursor::new(&mut output);
+    /// Uses order-1 contexts only if `options` asks for them.
+    pub fn with_options(mut self, options: &CompressionOptions) -> Self {
+        self.order1 = options.order1();
+        self
+    }
 
-        let mut length_bytes = bytes.len().to_be_bytes();
-        output_cursor.write(&length_bytes)?;
+    /// Panics unless `max_code_length` is between 8 and [`MAX_CODE_LENGTH`].
+    pub fn with_max_code_length(mut self, max_code_length: usize) -> Self {
+        assert!((8..=MAX_CODE_LENGTH).contains(&max_code_length));
+        self.max_code_length = max_code_length;
+        self
+    }
 
-        let mut writer = BitWriter::new(output_cursor);
+    fn build_frequency_tables(&self, bytes: &[u8], mut previous: u8) -> Vec<[u64; 256]> {
+        let mut frequencies = vec![[0u64; 256]; 256];
 
-        let tables = self.build_frequency_tables(bytes);
-        let trees = tables.map(|frequencies| TreeNode::build(&frequencies).unwrap());
+        for &byte in bytes {
+            frequencies[previous as usize][byte as usize] += 1;
+            previous = byte;
+        }
 
-        for tree in &trees {
-            tree.encode(&mut writer)?;
+        frequencies
+    }
+
+    /// Picks the contexts that get a code of their own. The others share an
+    /// order-0 code, which is cheaper for contexts too rare to pay for their
+    /// own code table.
+    fn choose_codes(&self, tables: &[[u64; 256]]) -> (Vec<Option<CanonicalCode>>, CanonicalCode) {
+        if !self.order1 {
+            let codes = tables.iter().map(|_| None).collect();
+            return (codes, self.shared_code(tables, &[true; 256]));
         }
 
-        let codes = trees.map(|tree| tree.codes());
+        let mut codes: Vec<Option<CanonicalCode>> = tables
+            .iter()
+            .map(|frequencies| {
+                frequencies
+                    .iter()
+                    .any(|&frequency| frequency > 0)
+                    .then(|| CanonicalCode::from_frequencies(frequencies, self.max_code_length))
+            })
+            .collect();
+
+        let own_costs: Vec<u64> = codes
+            .iter()
+            .zip(tables)
+            .map(|(code, frequencies)| match code {
+                Some(code) => code.table_bits() + code.cost(frequencies).unwrap(),
+                None => 0,
+            })
+            .collect();
 
-        let mut previous = 0u8;
+        // Start with every context sharing, and refine the shared code to the
+        // contexts that end up using it
+        let mut shared_contexts: Vec<bool> = codes.iter().map(Option::is_some).collect();
 
-        for &byte in bytes {
-            let code = codes[previous as usize][byte as usize];
-            writer.write_bits(code.word as u32, code.len)?;
-            previous = byte;
+        for _ in 0..2 {
+            let shared = self.shared_code(tables, &shared_contexts);
+
+            for (context, frequencies) in tables.iter().enumerate() {
+                if shared_contexts[context] {
+                    shared_contexts[context] = shared
+                        .cost(frequencies)
+                        .is_some_and(|cost| cost < own_costs[context]);
+                }
+            }
         }
 
-        writer.pad_to_byte()?;
+        for (code, shared) in codes.iter_mut().zip(&shared_contexts) {
+            if *shared {
+                *code = None;
+            }
+        }
 
-        Ok(output)
+        let shared = self.shared_code(tables, &shared_contexts);
+        (codes, shared)
     }
 
-    pub fn decode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
-        let mut output = Vec::new();
+    fn shared_code(&self, tables: &[[u64; 256]], contexts: &[bool]) -> CanonicalCode {
+        let mut frequencies = [0u64; 256];
 
-        let mut input_cursor = Cursor::new(bytes);
+        for (table, _) in tables.iter().zip(contexts).filter(|(_, shared)| **shared) {
+            for (total, &frequency) in frequencies.iter_mut().zip(table) {
+                *total += frequency;
+            }
+        }
+
+        CanonicalCode::from_frequencies(&frequencies, self.max_code_length)
+    }
+
+    /// Returns the lengths of the blocks to code `bytes` in.
+    ///
+    /// Like zopfli, a block is split at the candidate point that minimizes
+    /// the estimated size of the two halves, as long as that beats keeping
+    /// it whole, and the halves are split again in turn.
+    fn split_blocks(&self, bytes: &[u8]) -> Vec<usize> {
+        let mut blocks = Vec::new();
+
+        if !bytes.is_empty() {
+            self.split_recursive(bytes, 0, &mut blocks);
+        }
+
+        blocks
+    }
+
+    fn split_recursive(&self, bytes: &[u8], previous: u8, blocks: &mut Vec<usize>) {
+        if bytes.len() < 2 * MIN_BLOCK_SIZE {
+            blocks.push(bytes.len());
+            return;
+        }
+
+        // Count every segment between two candidate points once, and sum them
+        // up into the statistics of both halves
+        let points: Vec<usize> = (0..=SPLIT_CANDIDATES)
+            .map(|k| bytes.len() * k / SPLIT_CANDIDATES)
+            .collect();
+
+        let segments: Vec<Vec<[u64; 256]>> = points
+            .windows(2)
+            .map(|range| {
+                let previous = if range[0] == 0 {
+                    previous
+                } else {
+                    bytes[range[0] - 1]
+                };
+                self.build_frequency_tables(&bytes[range[0]..range[1]], previous)
+            })
+            .collect();
+
+        let mut total = vec![[0u64; 256]; 256];
+        for segment in &segments {
+            add_tables(&mut total, segment, 1);
+        }
+
+        let whole = estimate_bits(&total);
+        let mut best = None;
+        let mut left = vec![[0u64; 256]; 256];
+        let mut right = total.clone();
+
+        for (k, segment) in segments.iter().enumerate().take(SPLIT_CANDIDATES - 1) {
+            add_tables(&mut left, segment, 1);
+            add_tables(&mut right, segment, -1);
+
+            let point = points[k + 1];
+            if point < MIN_BLOCK_SIZE || bytes.len() - point < MIN_BLOCK_SIZE {
+                continue;
+            }
+
+            let bits = estimate_bits(&left) + estimate_bits(&right);
+            if bits < whole && best.is_none_or(|(best_bits, _)| bits < best_bits) {
+                best = Some((bits, point));
+            }
+        }
+
+        match best {
+            Some((_, point)) => {
+                self.split_recursive(&bytes[..point], previous, blocks);
+                self.split_recursive(&bytes[point..], bytes[point - 1], blocks);
+            }
+            None => blocks.push(bytes.len()),
+        }
+    }
+
+    fn encode_block<W: Write>(
+        &self,
+        writer: &mut BitWriter<W>,
+        bytes: &[u8],
+        mut previous: u8,
+    ) -> io::Result<()> {
+        let tables = self.build_frequency_tables(bytes, previous);
+        let (codes, shared) = self.choose_codes(&tables);
+
+        // Contexts with a code of their own, if there are any
+        let order1 = codes.iter().any(Option::is_some);
+        writer.write_bit(order1)?;
+
+        for byte in codes.chunks(8).filter(|_| order1) {
+            let bits = byte
+                .iter()
+                .enumerate()
+                .filter(|(_, code)| code.is_some())
+                .fold(0, |bits, (bit, _)| bits | 1 << bit);
 
-        let mut length_bytes = [0u8; 8];
-        input_cursor.read(&mut length_bytes)?;
-        let length = usize::from_be_bytes(length_bytes);
+            writer.write_byte(bits)?;
+        }
+
+        shared.write(writer)?;
+
+        for code in codes.iter().flatten() {
+            code.write(writer)?;
+        }
+
+        for &byte in bytes {
+            let code = codes[previous as usize].as_ref().unwrap_or(&shared);
+            code.code(byte.into()).encode(writer)?;
+            previous = byte;
+        }
+
+        Ok(())
+    }
 
-        let mut reader = BitReader::<_, MSB>::new(input_cursor);
+    fn decode_block(
+        &self,
+        reader: &mut BitReader,
+        length: usize,
+        output: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        let mut bitmap = [0u8; 32];
 
-        let mut trees = [const {
-            TreeNode {
-                frequency: 0,
-                kind: TreeNodeKind::Leaf { byte: 0 },
+        if reader.read_bit()? {
+            for byte in &mut bitmap {
+                *byte = reader.read_bits(8)? as u8;
             }
-        }; 256];
+        }
+
+        let shared = CanonicalCode::read(reader, 256)?;
+        let mut codes = Vec::with_capacity(256);
 
-        for i in 0..256 {
-            trees[i] = TreeNode::decode(&mut reader)?;
+        for context in 0..256 {
+            if bitmap[context / 8] & (1 << (context % 8)) != 0 {
+                codes.push(Some(CanonicalCode::read(reader, 256)?));
+            } else {
+                codes.push(None);
+            }
         }
 
-        let mut previous = 0u8;
+        let mut previous = output.last().copied().unwrap_or(0);
 
         for _ in 0..length {
-            let byte = trees[previous as usize].decode_symbol(&mut reader)?;
+            let code = codes[previous as usize].as_ref().unwrap_or(&shared);
+            let byte = code.decode_symbol(reader)? as u8;
             output.push(byte);
             previous = byte;
         }
 
+        Ok(())
+    }
+}
+
+/// Adds `sign` times the counts of `other` to `tables`.
+fn add_tables(tables: &mut [[u64; 256]], other: &[[u64; 256]], sign: i64) {
+    for (table, other) in tables.iter_mut().zip(other) {
+        for (count, &other) in table.iter_mut().zip(other) {
+            *count = count.wrapping_add_signed(sign * other as i64);
+        }
+    }
+}
+
+/// Estimates the size of a block from the entropy of its contexts, charging
+/// every context the cheaper of a code of its own and the shared order-0
+/// code, as [`HuffmanCoder::choose_codes`] does.
+fn estimate_bits(tables: &[[u64; 256]]) -> f64 {
+    let mut order0 = [0u64; 256];
+    for table in tables {
+        for (total, &count) in order0.iter_mut().zip(table) {
+            *total += count;
+        }
+    }
+
+    let order0_total: u64 = order0.iter().sum();
+    let order0_distinct = order0.iter().filter(|&&count| count > 0).count();
+    let mut bits = 256.0 + order0_distinct as f64 * TABLE_BITS_PER_SYMBOL;
+
+    for table in tables {
+        let total: u64 = table.iter().sum();
+
+        if total == 0 {
+            continue;
+        }
+
+        let mut own = 0.0;
+        let mut shared = 0.0;
+
+        for (&count, &order0_count) in table.iter().zip(&order0).filter(|&(&count, _)| count > 0) {
+            own += count as f64 * (total as f64 / count as f64).log2() + TABLE_BITS_PER_SYMBOL;
+            shared += count as f64 * (order0_total as f64 / order0_count as f64).log2();
+        }
+
+        bits += own.min(shared);
+    }
+
+    bits
+}
+
+impl Default for HuffmanCoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Codec for HuffmanCoder {
+    fn name(&self) -> &'static str {
+        "markov-huffman"
+    }
+
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn encode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        let mut output_cursor = Cursor::new(&mut output);
+
+        varint::write_len(&mut output_cursor, bytes.len())?;
+
+        let blocks = self.split_blocks(bytes);
+
+        varint::write(&mut output_cursor, blocks.len() as u64)?;
+        for &block in &blocks {
+            varint::write(&mut output_cursor, block as u64)?;
+        }
+
+        let mut writer = BitWriter::new(output_cursor);
+        let mut start = 0;
+
+        for &block in &blocks {
+            let previous = if start == 0 { 0 } else { bytes[start - 1] };
+            self.encode_block(&mut writer, &bytes[start..start + block], previous)?;
+            start += block;
+        }
+
+        writer.pad_to_byte()?;
+
         Ok(output)
     }
 
-    fn build_frequency_tables(&self, bytes: &[u8]) -> [[u64; 256]; 256] {
-        let mut previous = 0u8;
-        let mut frequencies = [[0u64; 256]; 256];
+    fn decode(&self, bytes: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
 
-        for &byte in bytes {
-            frequencies[previous as usize][byte as usize] += 1;
-            previous = byte;
+        let mut input_cursor = Cursor::new(bytes);
+
+        let length = varint::read_len_at_most(&mut input_cursor, max_len)?;
+
+        let count = varint::read(&mut input_cursor)?;
+        let mut blocks = Vec::new();
+        let mut total = 0u64;
+
+        for _ in 0..count {
+            let block = varint::read(&mut input_cursor)?;
+            total = total.saturating_add(block);
+
+            if block == 0 || total > length as u64 {
+                return Err(malformed("Invalid block length"));
+            }
+
+            blocks.push(block as usize);
         }
 
-        frequencies
+        if total != length as u64 {
+            return Err(malformed("Invalid block length"));
+        }
+
+        let mut reader = BitReader::new(&bytes[input_cursor.position() as usize..]);
+
+        for block in blocks {
+            self.decode_block(&mut reader, block, &mut output)?;
+        }
+
+        Ok(output)
     }
 }
 
 impl Code {
     pub fn encode<W: Write>(&self, writer: &mut BitWriter<W>) -> io::Result<()> {
+        if self.len == 0 {
+            return Ok(());
+        }
+
         writer.write_bits(self.word as u32, self.len)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frequencies of the first `count` Fibonacci numbers, which give the
+    /// deepest possible Huffman tree.
+    fn fibonacci(count: usize) -> [u64; 256] {
+        let mut frequencies = [0u64; 256];
+        let (mut a, mut b) = (1, 1);
+
+        for frequency in &mut frequencies[..count] {
+            *frequency = a;
+            (a, b) = (b, a + b);
+        }
+
+        frequencies
+    }
+
+    #[test]
+    fn fibonacci_code_lengths_are_limited() {
+        let frequencies = fibonacci(64);
+
+        for max_length in 8..=MAX_CODE_LENGTH {
+            let code = CanonicalCode::from_frequencies(&frequencies, max_length);
+            let lengths: Vec<usize> = (0..64).map(|symbol| code.length(symbol) as usize).collect();
+
+            assert!(
+                lengths
+                    .iter()
+                    .all(|&length| (1..=max_length).contains(&length))
+            );
+
+            // Every limited code is still complete
+            let kraft: u64 = lengths
+                .iter()
+                .map(|&length| 1 << (MAX_CODE_LENGTH - length))
+                .sum();
+            assert_eq!(kraft, 1 << MAX_CODE_LENGTH);
+        }
+    }
+
+    #[test]
+    fn fibonacci_code_round_trip() {
+        let frequencies = fibonacci(64);
+        let symbols: Vec<u16> = (0..64).chain((0..64).rev()).collect();
+
+        for max_length in 8..=MAX_CODE_LENGTH {
+            let code = CanonicalCode::from_frequencies(&frequencies, max_length);
+
+            let mut output = Vec::new();
+            let mut writer = BitWriter::new(&mut output);
+            for &symbol in &symbols {
+                code.code(symbol).encode(&mut writer).unwrap();
+            }
+            writer.pad_to_byte().unwrap();
+
+            let mut reader = BitReader::new(&output);
+            for &symbol in &symbols {
+                assert_eq!(code.decode_symbol(&mut reader).unwrap(), symbol);
+            }
+        }
+    }
+
+    #[test]
+    fn full_alphabets_fit_their_code_lengths() {
+        // Skewed enough to want long codes, with every symbol present
+        let mut frequencies: Vec<u64> = fibonacci(60)
+            .iter()
+            .map(|&frequency| frequency.max(1))
+            .collect();
+        frequencies.resize(MAX_ALPHABET_SIZE, 1);
+
+        // 258 symbols need 9 bits, 256 of them fit in 8
+        let code = CanonicalCode::from_frequencies(&frequencies, 9);
+        assert!((0..MAX_ALPHABET_SIZE as u16).all(|symbol| code.length(symbol) <= 9));
+
+        let code = CanonicalCode::from_frequencies(&frequencies[..256], 8);
+        assert!((0..256).all(|symbol| code.length(symbol) == 8));
+    }
+
+    #[test]
+    #[should_panic(expected = "too many symbols")]
+    fn too_many_symbols_for_the_code_length() {
+        CanonicalCode::from_frequencies(&[1; MAX_ALPHABET_SIZE], 8);
+    }
+
+    #[test]
+    fn only_skewed_contexts_get_codes() {
+        // After `a` always comes `x` and after `b` always `y`, while the
+        // other bytes follow no context of their own
+        let mut bytes = b"axby".repeat(2000);
+        bytes.extend(b"the quick brown fox jumps over the lazy dog");
+
+        let coder = HuffmanCoder::new();
+        let tables = coder.build_frequency_tables(&bytes, 0);
+        let (codes, _) = coder.choose_codes(&tables);
+
+        let own: Vec<u8> = (0..=255)
+            .filter(|&context| codes[context as usize].is_some())
+            .collect();
+        assert!(own.contains(&b'a') && own.contains(&b'b'), "{own:?}");
+        assert!(own.len() <= 4, "{own:?}");
+
+        let encoded = coder.encode(&bytes).unwrap();
+        assert_eq!(coder.decode(&encoded, bytes.len()).unwrap(), bytes);
+    }
+
+    #[test]
+    fn order0_blocks_leave_out_the_bitmap() {
+        let bytes = b"axby".repeat(2000);
+        let order0 = HuffmanCoder::new().with_options(&CompressionOptions::from_level(1));
+
+        let tables = order0.build_frequency_tables(&bytes, 0);
+        let (codes, _) = order0.choose_codes(&tables);
+        assert!(codes.iter().all(Option::is_none));
+
+        let encoded = order0.encode(&bytes).unwrap();
+        assert_eq!(order0.decode(&encoded, bytes.len()).unwrap(), bytes);
+
+        // Two bits per byte of four equally likely ones, one flag bit and the
+        // shared code: no room for a 32-byte bitmap
+        assert!(encoded.len() < bytes.len() / 4 + 32, "{}", encoded.len());
+    }
+
+    /// `len` bytes drawn from `alphabet` by a linear congruential generator.
+    fn random(len: usize, alphabet: &[u8], seed: u64) -> Vec<u8> {
+        let mut state = seed;
+
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                alphabet[(state >> 33) as usize % alphabet.len()]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn blocks_split_where_the_statistics_change() {
+        let half = 3 * MIN_BLOCK_SIZE;
+        let bytes = [random(half, b"abcdefgh", 1), random(half, b"stuvwxyz", 2)].concat();
+
+        let coder = HuffmanCoder::new();
+        let blocks = coder.split_blocks(&bytes);
+
+        assert!(blocks.len() > 1, "{blocks:?}");
+        assert_eq!(blocks.iter().sum::<usize>(), bytes.len());
+        assert!(blocks.iter().all(|&block| block >= MIN_BLOCK_SIZE));
+
+        // The candidate points fall on eighths of the input, so one of them
+        // is at the change
+        assert!(
+            blocks
+                .iter()
+                .scan(0, |end, &block| {
+                    *end += block;
+                    Some(*end)
+                })
+                .any(|end| end == half),
+            "{blocks:?}"
+        );
+
+        let encoded = coder.encode(&bytes).unwrap();
+        assert_eq!(coder.decode(&encoded, bytes.len()).unwrap(), bytes);
+    }
+
+    #[test]
+    fn uniform_input_stays_one_block() {
+        let coder = HuffmanCoder::new();
+
+        let bytes = random(6 * MIN_BLOCK_SIZE, b"abcdefgh", 3);
+        assert_eq!(coder.split_blocks(&bytes), [bytes.len()]);
+
+        // Too short to split at all
+        let bytes = [
+            random(MIN_BLOCK_SIZE, b"abcdefgh", 4),
+            random(MIN_BLOCK_SIZE - 1, b"stuvwxyz", 5),
+        ]
+        .concat();
+        assert_eq!(coder.split_blocks(&bytes), [bytes.len()]);
+        assert!(coder.split_blocks(&[]).is_empty());
+    }
+
+    #[test]
+    fn fibonacci_data_round_trip() {
+        // Byte `i` occurs as often as the `i`-th Fibonacci number, spread out
+        // by a fixed permutation
+        let frequencies = fibonacci(24);
+        let mut bytes: Vec<u8> = (0..24u8)
+            .flat_map(|byte| std::iter::repeat_n(byte, frequencies[byte as usize] as usize))
+            .collect();
+        let len = bytes.len();
+        for i in 0..len {
+            bytes.swap(i, i * 7919 % len);
+        }
+
+        for max_length in 8..=MAX_CODE_LENGTH {
+            let coder = HuffmanCoder::new().with_max_code_length(max_length);
+            let encoded = coder.encode(&bytes).unwrap();
+            assert_eq!(coder.decode(&encoded, bytes.len()).unwrap(), bytes);
+        }
+    }
+}
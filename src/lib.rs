@@ -1,9 +1,22 @@
+pub mod adaptive_huffman;
+pub mod bit_reader;
 pub mod bwt;
 pub mod bwt_coder;
 pub mod bwt_huffman;
 pub mod bwt_mtf_rle_huffman;
+pub mod bzip2;
+pub mod checksum;
+pub mod codec;
+pub mod container;
+pub mod error;
 pub mod huffman;
 pub mod markov_arithmetic;
 pub mod mtf;
+pub mod multi_table;
 pub mod rans;
 pub mod rans_lib;
+pub mod rans_order1;
+pub mod seekable;
+pub mod stream;
+pub mod suffix_array;
+pub mod varint;
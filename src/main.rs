@@ -1,103 +1,317 @@
+use std::{
+    env,
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, IsTerminal, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+};
+
 use anyhow::bail;
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 
 use markov_huffman::{
-    bwt_coder::BWTCoder, bwt_huffman::BWTHuffmanCoder, bwt_mtf_rle_huffman::BwtMtfRleHuffmanCoder,
-    huffman::HuffmanCoder, markov_arithmetic::MarkovArithmeticCoder, rans::ANSCoder,
+    bzip2,
+    checksum::Checksum,
+    codec::{self, Codec, CompressionOptions},
+    seekable::SeekableDecoder,
+    stream::{Decoder, Encoder},
 };
 
-fn main() {
+/// Suffix appended to compressed files when no output is given.
+const SUFFIX: &str = ".mh";
+const BZIP2_SUFFIX: &str = ".bz2";
+
+fn main() -> ExitCode {
     if let Err(e) = app() {
         eprintln!("{e}");
+        return ExitCode::FAILURE;
     }
+
+    ExitCode::SUCCESS
 }
 
 fn app() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let args = Args::parse_from(expand_levels(env::args_os()));
 
     if (args.compress && args.decompress) || (!args.compress && !args.decompress) {
         bail!("Select one of --compress or --decompress");
     }
 
-    let input = std::fs::read(&args.input)?;
+    if args.bzip2 && args.algorithm.is_some() {
+        bail!("--algorithm cannot be combined with --bzip2");
+    }
+
+    if args.threads == 0 {
+        bail!("--threads must be at least 1");
+    }
+
+    if args.bzip2 && args.threads > 1 {
+        bail!("--threads cannot be combined with --bzip2");
+    }
 
-    match args.algorithm.as_str() {
-        "markov-huffman" => {
-            let coder = HuffmanCoder::new();
+    if args.range.is_some() && (args.compress || args.bzip2) {
+        bail!("--range only applies to decompressing the native format");
+    }
 
-            let output = if args.compress {
-                coder.encode(&input)?
-            } else {
-                coder.decode(&input)?
-            };
+    let options = CompressionOptions::from_level(args.level);
 
-            std::fs::write(&args.output, output)?;
-        }
+    let coder = if args.compress && !args.bzip2 {
+        let Some(algorithm) = &args.algorithm else {
+            bail!("Select an algorithm with --algorithm");
+        };
+
+        let Some(coder) = codec::by_name_with_options(algorithm, &options) else {
+            bail!("Unknown algorithm");
+        };
 
-        "bwt" => {
-            let coder = BWTCoder::new();
+        Some(coder)
+    } else {
+        None
+    };
 
-            let output = if args.compress {
-                coder.encode(&input)?
-            } else {
-                coder.decode(&input)?
-            };
+    let input_path = args.input.as_deref().filter(|&path| path != "-");
 
-            std::fs::write(&args.output, output)?;
+    if args.range.is_some() && input_path.is_none() {
+        bail!("--range needs an input file to seek in");
+    }
+    let suffix = if args.bzip2 { BZIP2_SUFFIX } else { SUFFIX };
+
+    // A range is a slice of the data, not a replacement for its file
+    let output_path = match (args.stdout, args.output.as_deref(), input_path) {
+        (true, _, _) | (false, Some("-"), _) | (false, None, None) => None,
+        (false, None, Some(_)) if args.range.is_some() => None,
+        (false, Some(path), _) => Some(PathBuf::from(path)),
+        (false, None, Some(input)) => Some(default_output(input, suffix, args.compress)?),
+    };
+
+    // Like gzip, the input is only replaced when the output name is derived from it
+    let remove_input = !args.keep && args.output.is_none() && output_path.is_some();
+
+    if let (Some(input), Some(output)) = (input_path, &output_path)
+        && same_file(Path::new(input), output)
+    {
+        bail!("{input} and {} are the same file", output.display());
+    }
+
+    match &output_path {
+        Some(path) if path.exists() && !args.force => {
+            bail!(
+                "{} already exists, use --force to overwrite",
+                path.display()
+            );
         }
 
-        "bwt-huffman" => {
-            let coder = BWTHuffmanCoder::new();
+        None if args.compress && io::stdout().is_terminal() && !args.force => {
+            bail!("Compressed data not written to a terminal, use --force to override");
+        }
+
+        _ => {}
+    }
 
-            let output = if args.compress {
-                coder.encode(&input)?
-            } else {
-                coder.decode(&input)?
-            };
+    let output: Box<dyn Write> = match &output_path {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
 
-            std::fs::write(&args.output, output)?;
+    let result = if let (Some(range), Some(path)) = (args.range, input_path) {
+        run_range(File::open(path)?, range, output)
+    } else {
+        let (input, size): (Box<dyn Read>, _) = match input_path {
+            Some(path) => {
+                let file = File::open(path)?;
+                // Pipes and devices have no length to announce
+                let metadata = file.metadata()?;
+                let size = metadata.is_file().then_some(metadata.len());
+                (Box::new(BufReader::new(file)), size)
+            }
+            None => (Box::new(io::stdin().lock()), None),
+        };
+
+        if args.bzip2 {
+            run_bzip2(args.compress, args.level, input, output)
+        } else {
+            run(
+                coder,
+                input,
+                size,
+                output,
+                args.checksum,
+                &options,
+                args.threads,
+            )
         }
+    };
 
-        "markov-arithmetic" => {
-            let coder = MarkovArithmeticCoder::new();
+    if let Err(e) = result {
+        if let Some(path) = &output_path {
+            let _ = fs::remove_file(path);
+        }
 
-            let output = if args.compress {
-                coder.encode(&input)?
-            } else {
-                coder.decode(&input)?
-            };
+        return Err(e.into());
+    }
+
+    if let Some(path) = input_path.filter(|_| remove_input) {
+        fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
 
-            std::fs::write(&args.output, output)?;
+fn run(
+    coder: Option<Box<dyn Codec>>,
+    mut input: impl Read,
+    size: Option<u64>,
+    mut output: impl Write,
+    checksum: Checksum,
+    options: &CompressionOptions,
+    threads: usize,
+) -> io::Result<()> {
+    match coder {
+        Some(coder) => {
+            let mut encoder = Encoder::new(output, coder, checksum)?
+                .with_block_size(options.block_size())
+                .with_threads(threads);
+
+            if let Some(size) = size {
+                encoder = encoder.with_size_hint(size);
+            }
+
+            io::copy(&mut input, &mut encoder)?;
+            encoder.finish()?;
         }
 
-        "bwt-mtf-rle-huffman" => {
-            let coder = BwtMtfRleHuffmanCoder::new();
+        None => {
+            let mut decoder = Decoder::new(input)?.with_threads(threads);
+            io::copy(&mut decoder, &mut output)?;
+            output.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes `range` of the uncompressed data, decoding only the blocks it
+/// covers.
+fn run_range(input: File, range: Range, mut output: impl Write) -> io::Result<()> {
+    let mut decoder = SeekableDecoder::new(BufReader::new(input))?;
+    decoder.seek(SeekFrom::Start(range.start))?;
+    io::copy(&mut decoder.take(range.len), &mut output)?;
+    output.flush()
+}
+
+/// Reads and writes `.bz2` streams, whose levels match ours. Decompression
+/// reads the whole input, as the stream is decoded from memory.
+fn run_bzip2(
+    compress: bool,
+    level: u32,
+    mut input: impl Read,
+    mut output: impl Write,
+) -> io::Result<()> {
+    if compress {
+        let mut encoder = bzip2::Encoder::new(output, level)?;
+        io::copy(&mut input, &mut encoder)?;
+        encoder.finish()?;
+    } else {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes)?;
+        output.write_all(&bzip2::decompress(&bytes)?)?;
+        output.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Turns the `-1` to `-9` shorthands into `--level` where an option can
+/// stand: not as the value of the option before, such as `-o -3`, and not
+/// after `--`.
+fn expand_levels(args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    let command = Args::command();
+    let takes_value = |arg: &clap::Arg| arg.get_action().takes_values();
 
-            let output = if args.compress {
-                coder.encode(&input)?
-            } else {
-                coder.decode(&input)?
-            };
+    let mut args = args.into_iter();
+    let mut expanded: Vec<OsString> = args.next().into_iter().collect();
 
-            std::fs::write(&args.output, output)?;
+    while let Some(arg) = args.next() {
+        let Some(text) = arg.to_str() else {
+            expanded.push(arg);
+            continue;
+        };
+
+        if text == "--" {
+            expanded.push(arg);
+            expanded.extend(args);
+            break;
         }
 
-        "ans" => {
-            let mut coder = ANSCoder::new();
+        // Whether the next argument is the value of this one
+        let value_follows = if let Some(long) = text.strip_prefix("--") {
+            command
+                .get_arguments()
+                .any(|a| a.get_long() == Some(long) && takes_value(a))
+        } else if let Some(shorts) = text.strip_prefix('-') {
+            // In a group such as `-kofile`, the first option that takes a
+            // value takes the rest of the group, or the next argument
+            match shorts.char_indices().find(|&(_, short)| {
+                command
+                    .get_arguments()
+                    .any(|a| a.get_short() == Some(short) && takes_value(a))
+            }) {
+                Some((i, short)) => i + short.len_utf8() == shorts.len(),
+                None => false,
+            }
+        } else {
+            false
+        };
 
-            let output = if args.compress {
-                coder.encode(&input)?
-            } else {
-                coder.decode(&input)?
-            };
+        match text.strip_prefix('-') {
+            Some(level @ ("1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9")) => {
+                expanded.push(format!("--level={level}").into());
+            }
+            _ => expanded.push(arg),
+        }
 
-            std::fs::write(&args.output, output)?;
+        if value_follows {
+            expanded.extend(args.next());
         }
+    }
+
+    expanded
+}
+
+/// Whether both paths lead to the same existing file, also through links.
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) if a == b => true,
+        _ => same_inode(a, b),
+    }
+}
+
+#[cfg(unix)]
+fn same_inode(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
 
-        _ => bail!("Unknown algorithm"),
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
     }
+}
 
-    Ok(())
+#[cfg(not(unix))]
+fn same_inode(_: &Path, _: &Path) -> bool {
+    false
+}
+
+fn default_output(input: &str, suffix: &str, compress: bool) -> anyhow::Result<PathBuf> {
+    if compress {
+        return Ok(PathBuf::from(format!("{input}{suffix}")));
+    }
+
+    match input.strip_suffix(suffix) {
+        Some(stem) if !stem.is_empty() => Ok(PathBuf::from(stem)),
+        _ => bail!("{input}: unknown suffix, use --output or --stdout"),
+    }
 }
 
 #[derive(Parser)]
@@ -108,12 +322,125 @@ struct Args {
     #[arg(short, long)]
     decompress: bool,
 
+    /// Input file; stdin if omitted or `-`
     #[arg(short, long)]
-    input: String,
+    input: Option<String>,
 
+    /// Output file; stdout if `-`. Defaults to the input name with the `.mh`
+    /// suffix (`.bz2` with --bzip2) added or removed, and stdout when reading
+    /// stdin or with --range
     #[arg(short, long)]
-    output: String,
+    output: Option<String>,
+
+    /// Write to stdout and keep the input
+    #[arg(long)]
+    stdout: bool,
 
+    /// Keep the input file when the output name is derived from it
     #[arg(short, long)]
-    algorithm: String,
+    keep: bool,
+
+    /// Overwrite existing output and write compressed data to a terminal
+    #[arg(short, long)]
+    force: bool,
+
+    /// Algorithm to compress with; detected from the file when decompressing
+    #[arg(short, long)]
+    algorithm: Option<String>,
+
+    /// Read and write `.bz2` streams compatible with the bzip2 tool instead
+    /// of the native format
+    #[arg(long)]
+    bzip2: bool,
+
+    /// Compression level from 1 (fastest, least memory) to 9 (best ratio),
+    /// also given as -1 to -9. Sets the block size, the number of Huffman
+    /// tables of bwt-multi-huffman and the model order of markov-huffman and
+    /// bwt-huffman; the other codecs keep the order their name gives.
+    /// Decompression does not need it
+    #[arg(
+        long,
+        default_value_t = CompressionOptions::MAX_LEVEL,
+        value_parser = clap::value_parser!(u32).range(1..=9),
+    )]
+    level: u32,
+
+    /// Number of blocks to compress or decompress in parallel; the output
+    /// does not depend on it
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+
+    /// Decompress only LEN bytes starting at byte START of the uncompressed
+    /// data, to stdout unless --output is given
+    #[arg(long, value_name = "START:LEN")]
+    range: Option<Range>,
+
+    /// Checksum stored with compressed data: none, crc32 or xxhash
+    #[arg(long, default_value_t = Checksum::Crc32)]
+    checksum: Checksum,
+}
+
+/// Byte range of the uncompressed data.
+#[derive(Clone, Copy)]
+struct Range {
+    start: u64,
+    len: u64,
+}
+
+impl FromStr for Range {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |value: &str| {
+            value
+                .parse()
+                .map_err(|_| format!("Invalid range {s}, expected START:LEN"))
+        };
+
+        let (start, len) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid range {s}, expected START:LEN"))?;
+
+        Ok(Range {
+            start: parse(start)?,
+            len: parse(len)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(args: &[&str]) -> Vec<String> {
+        let args = ["markov-huffman"].iter().chain(args).map(OsString::from);
+
+        expand_levels(args)
+            .into_iter()
+            .skip(1)
+            .map(|arg| arg.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn levels_expand_in_option_position() {
+        assert_eq!(expand(&["-c", "-3", "-k"]), ["-c", "--level=3", "-k"]);
+        assert_eq!(expand(&["-9"]), ["--level=9"]);
+        assert_eq!(expand(&["-0", "-10", "--1"]), ["-0", "-10", "--1"]);
+    }
+
+    #[test]
+    fn option_values_are_left_alone() {
+        assert_eq!(expand(&["-o", "-3", "-5"]), ["-o", "-3", "--level=5"]);
+        assert_eq!(expand(&["--input", "-3"]), ["--input", "-3"]);
+        assert_eq!(expand(&["-ko", "-3"]), ["-ko", "-3"]);
+        assert_eq!(expand(&["-o-3", "-3"]), ["-o-3", "--level=3"]);
+        assert_eq!(expand(&["--output=-3", "-3"]), ["--output=-3", "--level=3"]);
+        assert_eq!(expand(&["-c", "-k", "-2"]), ["-c", "-k", "--level=2"]);
+    }
+
+    #[test]
+    fn nothing_expands_after_double_dash() {
+        assert_eq!(expand(&["-1", "--", "-2"]), ["--level=1", "--", "-2"]);
+    }
 }
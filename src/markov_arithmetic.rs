@@ -1,28 +1,39 @@
-#![allow(unused)]
-
-use std::{
-    io::{self, Cursor, Read, Write},
-    marker::PhantomData,
-    usize,
-};
+use std::io::{self, Cursor};
 
 use arcode::{ArithmeticDecoder, ArithmeticEncoder, Model};
 use bitbit::{BitReader, BitWriter, MSB};
 
-pub struct MarkovArithmeticCoder {
-    p: PhantomData<()>,
-}
+use crate::codec::Codec;
+use crate::varint;
+
+/// Arithmetic coder with an adaptive model per previous byte.
+///
+/// Layout: LEB128 original length, then one arithmetic-coded bit stream in
+/// which every byte is coded with the model of the byte before it, 0 for the
+/// first one.
+#[derive(Default)]
+pub struct MarkovArithmeticCoder;
 
 impl MarkovArithmeticCoder {
     pub fn new() -> Self {
-        MarkovArithmeticCoder { p: PhantomData }
+        MarkovArithmeticCoder
+    }
+}
+
+impl Codec for MarkovArithmeticCoder {
+    fn name(&self) -> &'static str {
+        "markov-arithmetic"
+    }
+
+    fn id(&self) -> u8 {
+        4
     }
 
-    pub fn encode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+    fn encode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
         let mut output = Vec::new();
         let mut output_cursor = Cursor::new(&mut output);
 
-        output_cursor.write(&bytes.len().to_be_bytes())?;
+        varint::write_len(&mut output_cursor, bytes.len())?;
 
         let mut writer = BitWriter::new(output_cursor);
 
@@ -30,36 +41,28 @@ impl MarkovArithmeticCoder {
             .map(|_| Model::builder().num_symbols(256).build())
             .collect::<Vec<_>>();
 
-        let mut coders = (0..256)
-            .map(|_| ArithmeticEncoder::new(48))
-            .collect::<Vec<_>>();
+        let mut encoder = ArithmeticEncoder::new(48);
 
         let mut previous = 0u8;
 
         for &byte in bytes {
-            coders[previous as usize].encode(
-                byte as u32,
-                &models[previous as usize],
-                &mut writer,
-            )?;
+            encoder.encode(byte as u32, &models[previous as usize], &mut writer)?;
 
             models[previous as usize].update_symbol(byte as u32);
             previous = byte;
         }
 
-        coders[previous as usize].finish_encode(&mut writer)?;
+        encoder.finish_encode(&mut writer)?;
         writer.pad_to_byte()?;
 
         Ok(output)
     }
 
-    pub fn decode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+    fn decode(&self, bytes: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
         let mut output = Vec::new();
         let mut input_cursor = Cursor::new(bytes);
 
-        let mut length_bytes = [0u8; 8];
-        input_cursor.read(&mut length_bytes)?;
-        let length = usize::from_be_bytes(length_bytes);
+        let length = varint::read_len_at_most(&mut input_cursor, max_len)?;
 
         let mut reader = BitReader::<_, MSB>::new(input_cursor);
 
@@ -67,14 +70,12 @@ impl MarkovArithmeticCoder {
             .map(|_| Model::builder().num_symbols(256).build())
             .collect::<Vec<_>>();
 
-        let mut coders = (0..256)
-            .map(|_| ArithmeticDecoder::new(48))
-            .collect::<Vec<_>>();
+        let mut decoder = ArithmeticDecoder::new(48);
 
         let mut previous = 0u8;
 
         for _ in 0..length {
-            let byte = coders[previous as usize].decode(&models[previous as usize], &mut reader)?;
+            let byte = decoder.decode(&models[previous as usize], &mut reader)?;
             models[previous as usize].update_symbol(byte);
             output.push(byte as u8);
             previous = byte as u8;
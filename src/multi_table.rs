@@ -0,0 +1,298 @@
+//! Huffman coding with several code tables, as in bzip2.
+//!
+//! The symbols are cut into groups of [`GROUP_SIZE`], and every group is
+//! coded with whichever of up to [`MAX_TABLES`] tables suits it best. The
+//! tables start out covering different frequency ranges of the alphabet and
+//! are refined by assigning the groups to their cheapest table and rebuilding
+//! every table from the groups assigned to it.
+
+use std::io::{self, Write};
+
+use bitbit::BitWriter;
+
+use crate::{
+    bit_reader::BitReader,
+    error::malformed,
+    huffman::{CanonicalCode, DEFAULT_MAX_CODE_LENGTH, MAX_ALPHABET_SIZE, MAX_CODE_LENGTH},
+};
+
+pub const GROUP_SIZE: usize = 50;
+pub const MIN_TABLES: usize = 2;
+pub const MAX_TABLES: usize = 6;
+const ITERATIONS: usize = 4;
+
+/// Number of tables worth their header for `count` symbols, as chosen by
+/// bzip2.
+fn table_count(count: usize) -> usize {
+    match count {
+        0..200 => MIN_TABLES,
+        200..600 => 3,
+        600..1200 => 4,
+        1200..2400 => 5,
+        _ => MAX_TABLES,
+    }
+}
+
+/// Writes `symbols` of an alphabet of `alphabet_size` symbols as the number
+/// of tables (3 bits), the table selector of every group, move-to-front and
+/// unary coded, the tables and the coded symbols. The number of symbols is
+/// not written.
+///
+/// Panics unless `max_tables` is between [`MIN_TABLES`] and [`MAX_TABLES`],
+/// or if `alphabet_size` is larger than [`MAX_ALPHABET_SIZE`].
+pub fn encode<W: Write>(
+    writer: &mut BitWriter<W>,
+    symbols: &[u16],
+    alphabet_size: usize,
+    max_tables: usize,
+) -> io::Result<()> {
+    let builder = TableBuilder::new(alphabet_size).with_max_tables(max_tables);
+
+    if symbols.is_empty() {
+        return Ok(());
+    }
+
+    let (tables, selectors) = builder.build(symbols);
+
+    writer.write_bits(tables.len() as u32, 3)?;
+
+    for position in selector_positions(&selectors, tables.len()) {
+        for _ in 0..position {
+            writer.write_bit(true)?;
+        }
+        writer.write_bit(false)?;
+    }
+
+    for table in &tables {
+        table.write(writer)?;
+    }
+
+    for (group, &selector) in symbols.chunks(GROUP_SIZE).zip(&selectors) {
+        let table = &tables[selector as usize];
+
+        for &symbol in group {
+            table.code(symbol).encode(writer)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads `count` symbols of an alphabet of `alphabet_size` symbols written
+/// by [`encode`].
+///
+/// Panics if `alphabet_size` is larger than [`MAX_ALPHABET_SIZE`].
+pub fn decode(reader: &mut BitReader, count: usize, alphabet_size: usize) -> io::Result<Vec<u16>> {
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+
+    let table_count = reader.read_bits(3)? as usize;
+
+    if !(MIN_TABLES..=MAX_TABLES).contains(&table_count) {
+        return Err(malformed("Invalid number of Huffman tables"));
+    }
+
+    let selectors = read_selectors(reader, count.div_ceil(GROUP_SIZE), table_count)?;
+    let mut tables = Vec::with_capacity(table_count);
+
+    for _ in 0..table_count {
+        tables.push(CanonicalCode::read(reader, alphabet_size)?);
+    }
+
+    let mut symbols = Vec::new();
+
+    for (group, &selector) in selectors.iter().enumerate() {
+        let table = &tables[selector as usize];
+        let end = count.min((group + 1) * GROUP_SIZE);
+
+        while symbols.len() < end {
+            symbols.push(table.decode_symbol(reader)?);
+        }
+    }
+
+    Ok(symbols)
+}
+
+/// Returns the move-to-front position of every selector among `table_count`
+/// tables, which is written in unary.
+pub fn selector_positions(selectors: &[u8], table_count: usize) -> Vec<usize> {
+    let mut order: Vec<u8> = (0..table_count as u8).collect();
+
+    selectors
+        .iter()
+        .map(|&selector| {
+            let position = order.iter().position(|&table| table == selector).unwrap();
+            order[..=position].rotate_right(1);
+            position
+        })
+        .collect()
+}
+
+/// Reads `count` selectors among `table_count` tables, written as the unary
+/// positions of [`selector_positions`].
+pub fn read_selectors(
+    reader: &mut BitReader,
+    count: usize,
+    table_count: usize,
+) -> io::Result<Vec<u8>> {
+    let mut order: Vec<u8> = (0..table_count as u8).collect();
+    // Grown as the input is read rather than trusting `count` for allocation
+    let mut selectors = Vec::new();
+
+    for _ in 0..count {
+        let mut position = 0;
+
+        while reader.read_bit()? {
+            position += 1;
+
+            if position == table_count {
+                return Err(malformed("Invalid Huffman table selector"));
+            }
+        }
+
+        order[..=position].rotate_right(1);
+        selectors.push(order[0]);
+    }
+
+    Ok(selectors)
+}
+
+/// Chooses the tables for a stream of symbols and the table of every group.
+pub struct TableBuilder {
+    alphabet_size: usize,
+    max_tables: usize,
+    max_code_length: usize,
+    every_symbol: bool,
+}
+
+impl TableBuilder {
+    /// Panics if `alphabet_size` is larger than [`MAX_ALPHABET_SIZE`].
+    pub fn new(alphabet_size: usize) -> Self {
+        assert!(alphabet_size <= MAX_ALPHABET_SIZE);
+
+        TableBuilder {
+            alphabet_size,
+            max_tables: MAX_TABLES,
+            max_code_length: DEFAULT_MAX_CODE_LENGTH,
+            every_symbol: false,
+        }
+    }
+
+    /// Panics unless `max_tables` is between [`MIN_TABLES`] and
+    /// [`MAX_TABLES`].
+    pub fn with_max_tables(mut self, max_tables: usize) -> Self {
+        assert!((MIN_TABLES..=MAX_TABLES).contains(&max_tables));
+        self.max_tables = max_tables;
+        self
+    }
+
+    /// Panics unless `max_code_length` is between 8 and [`MAX_CODE_LENGTH`]
+    /// and leaves room for a code per symbol of the alphabet.
+    pub fn with_max_code_length(mut self, max_code_length: usize) -> Self {
+        assert!((8..=MAX_CODE_LENGTH).contains(&max_code_length));
+        assert!(self.alphabet_size <= 1 << max_code_length);
+        self.max_code_length = max_code_length;
+        self
+    }
+
+    /// Gives every symbol of the alphabet a code, not only the ones that
+    /// occur, as bzip2 requires.
+    pub fn with_every_symbol(mut self, every_symbol: bool) -> Self {
+        self.every_symbol = every_symbol;
+        self
+    }
+
+    /// Returns the tables and the table chosen for every group of
+    /// [`GROUP_SIZE`] symbols.
+    ///
+    /// Panics if a symbol is outside the alphabet.
+    pub fn build(&self, symbols: &[u16]) -> (Vec<CanonicalCode>, Vec<u8>) {
+        let alphabet_size = self.alphabet_size;
+
+        let mut frequencies = vec![0u64; alphabet_size];
+        for &symbol in symbols {
+            frequencies[symbol as usize] += 1;
+        }
+
+        let table_count = table_count(symbols.len()).min(self.max_tables);
+
+        // Start with tables that are cheap for consecutive ranges of symbols
+        // of about equal total frequency, and expensive for the rest
+        let mut lengths = vec![vec![0u8; alphabet_size]; table_count];
+        let mut remaining = symbols.len() as u64;
+        let mut start = 0;
+
+        for (t, lengths) in lengths.iter_mut().enumerate() {
+            let target = remaining / (table_count - t) as u64;
+            let mut end = start;
+            let mut sum = 0;
+
+            while end < alphabet_size && (sum < target || end == start) {
+                sum += frequencies[end];
+                end += 1;
+            }
+
+            for (symbol, length) in lengths.iter_mut().enumerate() {
+                *length = if (start..end).contains(&symbol) {
+                    0
+                } else {
+                    15
+                };
+            }
+
+            remaining -= sum;
+            start = end;
+        }
+
+        let mut tables = Vec::new();
+        let mut selectors = Vec::new();
+
+        for _ in 0..ITERATIONS {
+            let mut table_frequencies = vec![vec![0u64; alphabet_size]; table_count];
+            selectors.clear();
+
+            for group in symbols.chunks(GROUP_SIZE) {
+                let best = (0..table_count)
+                    .min_by_key(|&t| {
+                        group
+                            .iter()
+                            .map(|&symbol| lengths[t][symbol as usize] as u32)
+                            .sum::<u32>()
+                    })
+                    .unwrap();
+
+                selectors.push(best as u8);
+
+                for &symbol in group {
+                    table_frequencies[best][symbol as usize] += 1;
+                }
+            }
+
+            // Every table keeps a code for every symbol that occurs, so that
+            // any group can be coded with it
+            tables = table_frequencies
+                .iter()
+                .map(|table| {
+                    let mut smoothed = table.clone();
+
+                    for (count, &frequency) in smoothed.iter_mut().zip(&frequencies) {
+                        if frequency > 0 || self.every_symbol {
+                            *count += 1;
+                        }
+                    }
+
+                    CanonicalCode::from_frequencies(&smoothed, self.max_code_length)
+                })
+                .collect();
+
+            for (lengths, table) in lengths.iter_mut().zip(&tables) {
+                for (symbol, length) in lengths.iter_mut().enumerate() {
+                    *length = table.length(symbol as u16);
+                }
+            }
+        }
+
+        (tables, selectors)
+    }
+}
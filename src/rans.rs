@@ -1,196 +1,406 @@
-use std::io;
+use std::io::{self, Read};
+
+use crate::{codec::Codec, error::malformed, varint};
+
+pub(crate) const RANS_BYTE_L: u32 = 1 << 23; // Lower bound for renormalization
+
+pub const DEFAULT_SCALE_BITS: u32 = 14;
+// Keeps every frequency within the 16 bits of a decoding slot
+pub(crate) const MAX_SCALE_BITS: u32 = 15;
+pub const DEFAULT_STATES: usize = 4;
+
+/// Static order-0 rANS coder.
+///
+/// Symbol frequencies are quantized to sum to `1 << scale_bits`. Symbol `i`
+/// is coded with state `i % states`; the states share one byte stream, so
+/// decoding them round-robin gives the CPU independent work to overlap.
+///
+/// Layout:
+///
+/// | field       | size | description                                    |
+/// |-------------|------|------------------------------------------------|
+/// | length      | ...  | LEB128 original length                         |
+/// | scale bits  | 1    | log2 of the frequency total                    |
+/// | symbols     | 32   | bitmap of the symbols that occur               |
+/// | frequencies | ...  | LEB128 frequency of every symbol in the bitmap |
+/// | states      | 1    | number of interleaved states: 1, 2, 4 or 8     |
+/// | state       | 4    | final state of each encoder, little-endian     |
+/// | stream      | ...  | renormalization bytes in decoding order        |
+pub struct ANSCoder {
+    settings: Settings,
+}
 
-const RANS_BYTE_L: u32 = 1 << 23; // Lower bound for renormalization
+/// Frequency scale and number of interleaved states of a rANS coder.
+#[derive(Clone, Copy)]
+pub(crate) struct Settings {
+    pub(crate) scale_bits: u32,
+    pub(crate) states: usize,
+}
 
-pub struct ANSCoder {
-    freq: [u32; 256],
+impl Settings {
+    pub(crate) fn new(scale_bits: u32) -> Self {
+        Settings {
+            scale_bits,
+            states: DEFAULT_STATES,
+        }
+    }
+
+    /// Panics unless `scale_bits` is between 8 and 15.
+    pub(crate) fn with_scale_bits(mut self, scale_bits: u32) -> Self {
+        assert!((8..=MAX_SCALE_BITS).contains(&scale_bits));
+        self.scale_bits = scale_bits;
+        self
+    }
+
+    /// Panics unless `states` is 1, 2, 4 or 8.
+    pub(crate) fn with_states(mut self, states: usize) -> Self {
+        assert!(matches!(states, 1 | 2 | 4 | 8));
+        self.states = states;
+        self
+    }
+}
+
+pub(crate) struct FrequencyTable {
+    scale_bits: u32,
+    pub(crate) freq: [u32; 256],
     cum_freq: [u32; 257],
-    total_freq: u32,
+    slots: Vec<Slot>,
+}
+
+/// Decoding information for one of the `1 << scale_bits` slots.
+#[derive(Clone, Copy)]
+struct Slot {
+    symbol: u8,
+    freq: u16,
+    // Position of the slot within the symbol's range
+    offset: u16,
+}
+
+/// Scales symbol counts to frequencies that sum to `1 << scale_bits`. Every
+/// symbol that occurs keeps a frequency of at least one.
+pub fn normalize_frequencies(counts: &[u64; 256], scale_bits: u32) -> [u32; 256] {
+    assert!((8..=16).contains(&scale_bits));
+
+    let target = 1u32 << scale_bits;
+    let total: u64 = counts.iter().sum();
+    let mut freq = [0u32; 256];
+
+    if total == 0 {
+        return freq;
+    }
+
+    for (freq, &count) in freq.iter_mut().zip(counts) {
+        if count > 0 {
+            let scaled = count as u128 * target as u128 / total as u128;
+            *freq = (scaled as u32).max(1);
+        }
+    }
+
+    let mut sum: u32 = freq.iter().sum();
+
+    // Rounding down leaves a deficit, which goes to the most frequent symbol
+    if sum < target {
+        let largest = (0..256).max_by_key(|&i| freq[i]).unwrap();
+        freq[largest] += target - sum;
+        sum = target;
+    }
+
+    // Rounding rare symbols up to one can overshoot, so take the excess back
+    // from the largest frequencies
+    while sum > target {
+        let largest = (0..256).max_by_key(|&i| freq[i]).unwrap();
+        freq[largest] -= 1;
+        sum -= 1;
+    }
+
+    freq
 }
 
 impl ANSCoder {
     pub fn new() -> Self {
         Self {
-            freq: [0; 256],
-            cum_freq: [0; 257],
-            total_freq: 0,
+            settings: Settings::new(DEFAULT_SCALE_BITS),
         }
     }
 
-    fn build_frequency_table(&mut self, data: &[u8]) {
-        // Reset frequencies
-        self.freq.fill(0);
+    /// Panics unless `scale_bits` is between 8 and 15.
+    pub fn with_scale_bits(mut self, scale_bits: u32) -> Self {
+        self.settings = self.settings.with_scale_bits(scale_bits);
+        self
+    }
+
+    /// Panics unless `states` is 1, 2, 4 or 8.
+    pub fn with_states(mut self, states: usize) -> Self {
+        self.settings = self.settings.with_states(states);
+        self
+    }
+}
+
+impl Default for ANSCoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrequencyTable {
+    pub(crate) fn new(freq: [u32; 256], scale_bits: u32) -> Self {
+        let mut cum_freq = [0; 257];
+
+        // Build cumulative frequency table
+        for i in 0..256 {
+            cum_freq[i + 1] = cum_freq[i] + freq[i];
+        }
+
+        let mut slots = Vec::with_capacity(1 << scale_bits);
+        for (symbol, &freq) in freq.iter().enumerate() {
+            slots.extend((0..freq).map(|offset| Slot {
+                symbol: symbol as u8,
+                freq: freq as u16,
+                offset: offset as u16,
+            }));
+        }
+
+        Self {
+            scale_bits,
+            freq,
+            cum_freq,
+            slots,
+        }
+    }
+
+    fn build(data: &[u8], scale_bits: u32) -> Self {
+        let mut counts = [0u64; 256];
 
         // Count frequencies
         for &byte in data {
-            self.freq[byte as usize] += 1;
+            counts[byte as usize] += 1;
         }
 
-        // Ensure no zero frequencies (add 1 to each)
-        for freq in &mut self.freq {
-            *freq += 1;
+        Self::new(normalize_frequencies(&counts, scale_bits), scale_bits)
+    }
+
+    fn write(&self, output: &mut Vec<u8>) -> io::Result<()> {
+        output.push(self.scale_bits as u8);
+
+        let mut bitmap = [0u8; 32];
+        for (symbol, &freq) in self.freq.iter().enumerate() {
+            if freq > 0 {
+                bitmap[symbol / 8] |= 1 << (symbol % 8);
+            }
         }
 
-        // Build cumulative frequency table
-        self.cum_freq[0] = 0;
-        for i in 0..256 {
-            self.cum_freq[i + 1] = self.cum_freq[i] + self.freq[i];
+        output.extend_from_slice(&bitmap);
+
+        for &freq in self.freq.iter().filter(|&&freq| freq > 0) {
+            varint::write(output, freq as u64)?;
         }
-        self.total_freq = self.cum_freq[256];
+
+        Ok(())
     }
 
-    fn rans_encode_put(&self, state: &mut u32, output: &mut Vec<u8>, sym: u8) {
+    fn read<R: Read>(input: &mut R) -> io::Result<Self> {
+        let mut scale_bits = [0u8; 1];
+        input.read_exact(&mut scale_bits)?;
+        let scale_bits = scale_bits[0] as u32;
+
+        if !(8..=MAX_SCALE_BITS).contains(&scale_bits) {
+            return Err(malformed("Invalid frequency scale"));
+        }
+
+        let mut bitmap = [0u8; 32];
+        input.read_exact(&mut bitmap)?;
+
+        let mut freq = [0u32; 256];
+        let mut total = 0u64;
+
+        for (symbol, freq) in freq.iter_mut().enumerate() {
+            if bitmap[symbol / 8] & (1 << (symbol % 8)) != 0 {
+                let value = varint::read(input)?;
+                total += value;
+
+                if value == 0 || total > 1 << scale_bits {
+                    return Err(malformed("Invalid frequency table"));
+                }
+
+                *freq = value as u32;
+            }
+        }
+
+        if total != 1 << scale_bits {
+            return Err(malformed("Invalid frequency table"));
+        }
+
+        Ok(Self::new(freq, scale_bits))
+    }
+
+    pub(crate) fn rans_encode_put(&self, state: &mut u32, output: &mut Vec<u8>, sym: u8) {
         let symbol = sym as usize;
         let freq = self.freq[symbol];
         let start = self.cum_freq[symbol];
 
         // Renormalize if needed
-        while *state >= ((RANS_BYTE_L >> 8) << 8) * freq {
+        let x_max = ((RANS_BYTE_L >> self.scale_bits) << 8) * freq;
+        while *state >= x_max {
             output.push(*state as u8);
             *state >>= 8;
         }
 
         // Encode symbol
-        *state = ((*state / freq) << 8) + (*state % freq) + start;
+        *state = ((*state / freq) << self.scale_bits) + (*state % freq) + start;
     }
 
-    fn rans_decode_get<'a>(
+    pub(crate) fn rans_decode_get<'a>(
         &self,
         state: &mut u32,
         input: &mut impl Iterator<Item = &'a u8>,
     ) -> Option<u8> {
-        // Renormalize if needed
-        while *state < RANS_BYTE_L {
-            if let Some(&byte) = input.next() {
-                *state = (*state << 8) | (byte as u32);
-            } else {
-                return None;
-            }
-        }
+        // Look up the symbol owning the slot
+        let mask = (1 << self.scale_bits) - 1;
+        let slot = self.slots[(*state & mask) as usize];
 
-        // Decode symbol
-        let cum = *state % self.total_freq;
+        *state = slot.freq as u32 * (*state >> self.scale_bits) + slot.offset as u32;
 
-        // Find symbol using binary search
-        let mut symbol = 0;
-        for i in 0..256 {
-            if self.cum_freq[i + 1] > cum {
-                symbol = i;
-                break;
-            }
+        // Renormalize if needed
+        while *state < RANS_BYTE_L {
+            *state = (*state << 8) | (*input.next()? as u32);
         }
 
-        let freq = self.freq[symbol];
-        let start = self.cum_freq[symbol];
-
-        *state = freq * (*state / self.total_freq) + cum - start;
-
-        Some(symbol as u8)
+        Some(slot.symbol)
     }
+}
 
-    pub fn encode(&mut self, bytes: &[u8]) -> io::Result<Vec<u8>> {
-        if bytes.is_empty() {
-            return Ok(Vec::new());
-        }
+impl Codec for ANSCoder {
+    fn name(&self) -> &'static str {
+        "ans"
+    }
 
-        self.build_frequency_table(bytes);
+    fn id(&self) -> u8 {
+        6
+    }
 
+    fn encode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
         let mut output = Vec::new();
-        let mut state = RANS_BYTE_L;
 
-        // Write frequency table to output
-        for &freq in &self.freq {
-            output.extend_from_slice(&freq.to_le_bytes());
-        }
+        // Write original length
+        varint::write_len(&mut output, bytes.len())?;
 
-        // Encode symbols in reverse order
-        for &byte in bytes.iter().rev() {
-            self.rans_encode_put(&mut state, &mut output, byte);
+        if bytes.is_empty() {
+            return Ok(output);
         }
 
-        // Write final state
-        output.extend_from_slice(&state.to_le_bytes());
+        let table = FrequencyTable::build(bytes, self.settings.scale_bits);
+        table.write(&mut output)?;
 
-        // Write original length
-        output.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        encode_interleaved(bytes, self.settings.states, &mut output, |_| &table);
 
         Ok(output)
     }
 
-    pub fn decode(&mut self, bytes: &[u8]) -> io::Result<Vec<u8>> {
-        if bytes.len() < 4 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid compressed data",
-            ));
-        }
-
-        let mut cursor = bytes.len();
+    fn decode(&self, bytes: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        let mut input = bytes;
 
         // Read original length
-        cursor -= 4;
-        let original_len = u32::from_le_bytes([
-            bytes[cursor],
-            bytes[cursor + 1],
-            bytes[cursor + 2],
-            bytes[cursor + 3],
-        ]) as usize;
+        let original_len = varint::read_len_at_most(&mut input, max_len)?;
 
         if original_len == 0 {
             return Ok(Vec::new());
         }
 
-        // Read final state
-        cursor -= 4;
-        let mut state = u32::from_le_bytes([
-            bytes[cursor],
-            bytes[cursor + 1],
-            bytes[cursor + 2],
-            bytes[cursor + 3],
-        ]);
-
         // Read frequency table
-        if cursor < 256 * 4 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid frequency table",
-            ));
-        }
+        let table = FrequencyTable::read(&mut input)?;
 
-        cursor -= 256 * 4;
-        for i in 0..256 {
-            let freq_bytes = [
-                bytes[cursor + i * 4],
-                bytes[cursor + i * 4 + 1],
-                bytes[cursor + i * 4 + 2],
-                bytes[cursor + i * 4 + 3],
-            ];
-            self.freq[i] = u32::from_le_bytes(freq_bytes);
-        }
+        decode_interleaved(input, original_len, |_| Some(&table))
+    }
+}
 
-        // Rebuild cumulative frequency table
-        self.cum_freq[0] = 0;
-        for i in 0..256 {
-            self.cum_freq[i + 1] = self.cum_freq[i] + self.freq[i];
-        }
-        self.total_freq = self.cum_freq[256];
-
-        // Decode symbols
-        let encoded_data = &bytes[..cursor];
-        let mut input_iter = encoded_data.iter().rev();
-        let mut output = Vec::with_capacity(original_len);
-
-        for _ in 0..original_len {
-            if let Some(symbol) = self.rans_decode_get(&mut state, &mut input_iter) {
-                output.push(symbol);
-            } else {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Unexpected end of input",
-                ));
-            }
+/// Codes `bytes` in reverse with the states taking turns, byte `i` with state
+/// `i % states` and the table `table` returns for the byte before it (0 for
+/// the first byte). Appends the number of states, their final values and
+/// the stream in the order the decoder reads it.
+pub(crate) fn encode_interleaved<'a>(
+    bytes: &[u8],
+    states: usize,
+    output: &mut Vec<u8>,
+    table: impl Fn(u8) -> &'a FrequencyTable,
+) {
+    let mut stream = Vec::new();
+    let mut finals = vec![RANS_BYTE_L; states];
+
+    for (i, &byte) in bytes.iter().enumerate().rev() {
+        let previous = if i == 0 { 0 } else { bytes[i - 1] };
+        table(previous).rans_encode_put(&mut finals[i % states], &mut stream, byte);
+    }
+
+    output.push(states as u8);
+    for state in &finals {
+        output.extend_from_slice(&state.to_le_bytes());
+    }
+    output.extend(stream.iter().rev());
+}
+
+/// Decodes `len` bytes written by [`encode_interleaved`], looking up the table
+/// of every byte by the byte before it.
+pub(crate) fn decode_interleaved<'a>(
+    input: &[u8],
+    len: usize,
+    table: impl Fn(u8) -> Option<&'a FrequencyTable>,
+) -> io::Result<Vec<u8>> {
+    let (&count, input) = input
+        .split_first()
+        .ok_or_else(|| malformed("Unexpected end of input"))?;
+
+    match count {
+        1 => decode_states::<1>(input, len, table),
+        2 => decode_states::<2>(input, len, table),
+        4 => decode_states::<4>(input, len, table),
+        8 => decode_states::<8>(input, len, table),
+        _ => Err(malformed("Invalid number of rANS states")),
+    }
+}
+
+fn decode_states<'a, const N: usize>(
+    mut input: &[u8],
+    len: usize,
+    table: impl Fn(u8) -> Option<&'a FrequencyTable>,
+) -> io::Result<Vec<u8>> {
+    let mut states = read_states::<N>(&mut input)?;
+
+    // Decode symbols, one from every state per round
+    let mut input_iter = input.iter();
+    let mut output = vec![0u8; len];
+    let mut previous = 0u8;
+
+    for round in output.chunks_mut(N) {
+        for (byte, state) in round.iter_mut().zip(&mut states) {
+            *byte = table(previous)
+                .ok_or_else(|| malformed("Missing context table"))?
+                .rans_decode_get(state, &mut input_iter)
+                .ok_or_else(|| malformed("Unexpected end of input"))?;
+            previous = *byte;
         }
+    }
 
-        Ok(output)
+    // The decoders end in the state the encoders started from
+    if states.iter().any(|&state| state != RANS_BYTE_L) || input_iter.next().is_some() {
+        return Err(malformed("Invalid rANS stream"));
     }
+
+    Ok(output)
+}
+
+/// Reads the final encoder states that the decoder starts from.
+fn read_states<const N: usize>(input: &mut &[u8]) -> io::Result<[u32; N]> {
+    let mut states = [0u32; N];
+
+    for state in &mut states {
+        let mut state_bytes = [0u8; 4];
+        input.read_exact(&mut state_bytes)?;
+        *state = u32::from_le_bytes(state_bytes);
+
+        if !(RANS_BYTE_L..RANS_BYTE_L << 8).contains(state) {
+            return Err(malformed("Invalid rANS state"));
+        }
+    }
+
+    Ok(states)
 }
@@ -1,21 +1,207 @@
-#![allow(unused)]
-
 use std::io;
 
-use rans::{RansEncSymbol, RansEncoder, byte_encoder::ByteRansEncSymbol};
+use rans::{
+    RansDecSymbol, RansDecoder, RansEncSymbol, RansEncoder, RansEncoderMulti,
+    byte_decoder::{ByteRansDecSymbol, ByteRansDecoder},
+    byte_encoder::{ByteRansEncSymbol, ByteRansEncoder},
+};
+
+use crate::{codec::Codec, error::malformed, rans::normalize_frequencies, varint};
+
+const SCALE_BITS: u32 = 14;
+
+const RANS_BYTE_L: u32 = 1 << 23;
 
+// States stay below 2^31, so reading the slot with this many bits returns
+// the whole state, which the library does not expose otherwise
+const STATE_BITS: u32 = 31;
+
+/// Static order-0 rANS coder built on the `rans` crate, used as a reference
+/// for [`ANSCoder`](crate::rans::ANSCoder).
+///
+/// Layout: LEB128 original length, 256 symbol frequencies
+/// (`u16`, little-endian) summing to `1 << SCALE_BITS`, then the rANS stream.
+#[derive(Default)]
 pub struct AnsLibraryCoder;
 
 impl AnsLibraryCoder {
     pub fn new() -> Self {
         Self
     }
+}
+
+impl Codec for AnsLibraryCoder {
+    fn name(&self) -> &'static str {
+        "ans-lib"
+    }
+
+    fn id(&self) -> u8 {
+        7
+    }
+
+    fn encode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        varint::write_len(&mut output, bytes.len())?;
+
+        if bytes.is_empty() {
+            return Ok(output);
+        }
+
+        let mut counts = [0u64; 256];
+        for &byte in bytes {
+            counts[byte as usize] += 1;
+        }
 
-    pub fn encode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
-        todo!()
+        let freq = normalize_frequencies(&counts, SCALE_BITS);
+
+        let mut symbols = Vec::with_capacity(256);
+        let mut start = 0;
+
+        for &freq in &freq {
+            output.extend_from_slice(&(freq as u16).to_le_bytes());
+            symbols.push(ByteRansEncSymbol::new(start, freq, SCALE_BITS));
+            start += freq;
+        }
+
+        // Every symbol emits at most two bytes, plus four for the final state
+        let mut encoder = ByteRansEncoder::new(bytes.len() * 2 + 4);
+
+        for &byte in bytes.iter().rev() {
+            encoder.put(&symbols[byte as usize]);
+        }
+
+        encoder.flush();
+        output.extend_from_slice(encoder.data());
+
+        Ok(output)
     }
 
-    pub fn decode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
-        todo!()
+    fn decode(&self, bytes: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        let mut bytes = bytes;
+        let length = varint::read_len_at_most(&mut bytes, max_len)?;
+
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+
+        if bytes.len() < 256 * 2 + 4 {
+            return Err(malformed("truncated rANS stream"));
+        }
+
+        let (table, data) = bytes.split_at(256 * 2);
+
+        let mut symbols = Vec::with_capacity(256);
+        let mut ranges = Vec::with_capacity(256);
+        let mut slots = Vec::with_capacity(1 << SCALE_BITS);
+
+        for (symbol, freq) in table.chunks_exact(2).enumerate() {
+            let freq = u16::from_le_bytes([freq[0], freq[1]]) as u32;
+
+            if slots.len() as u32 + freq > 1 << SCALE_BITS {
+                return Err(malformed("invalid frequency table"));
+            }
+
+            symbols.push(ByteRansDecSymbol::new(slots.len() as u32, freq));
+            ranges.push((slots.len() as u32, freq));
+            slots.extend(std::iter::repeat_n(symbol as u8, freq as usize));
+        }
+
+        if slots.len() != 1 << SCALE_BITS {
+            return Err(malformed("invalid frequency table"));
+        }
+
+        // The decoder does not check bounds. A valid final state keeps every
+        // renormalization to at most two bytes per symbol, so padding the
+        // stream to that length makes corrupted input safe to read.
+        let state = u32::from_le_bytes(data[..4].try_into().unwrap());
+
+        if !(RANS_BYTE_L..RANS_BYTE_L << 8).contains(&state) {
+            return Err(malformed("invalid rANS state"));
+        }
+
+        let padded = length.saturating_mul(2).saturating_add(4);
+        let mut stream = vec![0u8; padded.max(data.len())];
+        stream[..data.len()].copy_from_slice(data);
+
+        let mut decoder = ByteRansDecoder::new(stream);
+        let mut output = vec![0u8; length];
+
+        // Count the bytes the renormalizations read, as the library keeps its
+        // position to itself. This repeats the library's renormalization, so
+        // only the check of the final state is independent of it
+        let mut consumed = 4;
+
+        for byte in &mut output {
+            let state = decoder.get(STATE_BITS);
+            let slot = state & ((1 << SCALE_BITS) - 1);
+            let symbol = slots[slot as usize];
+
+            let (start, freq) = ranges[symbol as usize];
+            let mut next = freq * (state >> SCALE_BITS) + slot - start;
+
+            while next < RANS_BYTE_L {
+                next <<= 8;
+                consumed += 1;
+            }
+
+            decoder.advance(&symbols[symbol as usize], SCALE_BITS);
+            *byte = symbol;
+        }
+
+        // The decoder ends in the state the encoder started from, having read
+        // exactly the stream
+        if decoder.get(STATE_BITS) != RANS_BYTE_L || consumed != data.len() {
+            return Err(malformed("invalid rANS stream"));
+        }
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rans::{ANSCoder, DEFAULT_SCALE_BITS};
+
+    fn input() -> Vec<u8> {
+        std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/input.txt")).unwrap()
+    }
+
+    #[test]
+    fn table_is_the_quantization_of_ans() {
+        let input = input();
+        let encoded = AnsLibraryCoder::new().encode(&input).unwrap();
+
+        let mut counts = [0u64; 256];
+        for &byte in &input {
+            counts[byte as usize] += 1;
+        }
+
+        let mut rest = &encoded[..];
+        varint::read_len(&mut rest).unwrap();
+        let table: Vec<u32> = rest[..512]
+            .chunks_exact(2)
+            .map(|freq| u16::from_le_bytes([freq[0], freq[1]]) as u32)
+            .collect();
+
+        assert_eq!(SCALE_BITS, DEFAULT_SCALE_BITS);
+        assert_eq!(table, normalize_frequencies(&counts, DEFAULT_SCALE_BITS));
+    }
+
+    #[test]
+    fn stream_matches_ans_with_one_state() {
+        // Same table, same state bounds and byte-wise renormalization: the
+        // library and `ANSCoder` write the same final state and stream, and
+        // differ only in how they store the table
+        let input = input();
+        let library = AnsLibraryCoder::new().encode(&input).unwrap();
+        let ans = ANSCoder::new().with_states(1).encode(&input).unwrap();
+
+        let mut rest = &library[..];
+        varint::read_len(&mut rest).unwrap();
+        assert!(ans.ends_with(&rest[512..]));
+
+        // The tables differ in size by the stored frequencies alone
+        assert!(library.len().abs_diff(ans.len()) < 512);
     }
 }
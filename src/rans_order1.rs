@@ -0,0 +1,219 @@
+use std::io::{self, Read};
+
+use crate::{
+    codec::Codec,
+    error::malformed,
+    rans::{
+        FrequencyTable, MAX_SCALE_BITS, Settings, decode_interleaved, encode_interleaved,
+        normalize_frequencies,
+    },
+    varint,
+};
+
+pub const DEFAULT_SCALE_BITS: u32 = 12;
+
+/// Static order-1 rANS coder: every byte is coded with the frequency table of
+/// the byte before it, the first byte with the table of context 0.
+///
+/// Only the contexts that occur are transmitted. Within a context, symbols
+/// are listed in ascending order as the gap from the previous symbol, so a
+/// context followed by a handful of symbols costs a few bytes.
+///
+/// Layout:
+///
+/// | field      | size | description                                        |
+/// |------------|------|----------------------------------------------------|
+/// | length     | ...  | LEB128 original length                             |
+/// | scale bits | 1    | log2 of the frequency total of every context       |
+/// | contexts   | 32   | bitmap of the contexts that occur                  |
+/// | tables     | ...  | for every context in the bitmap, see below         |
+/// | states     | 1    | number of interleaved states: 1, 2, 4 or 8         |
+/// | state      | 4    | final state of each encoder, little-endian         |
+/// | stream     | ...  | renormalization bytes in decoding order            |
+///
+/// A context table is the LEB128 number of symbols minus one, followed by
+/// the LEB128 gap to the previous symbol (minus one, the first symbol as is)
+/// and the LEB128 frequency of every symbol.
+pub struct ANSOrder1Coder {
+    settings: Settings,
+}
+
+impl ANSOrder1Coder {
+    pub fn new() -> Self {
+        Self {
+            settings: Settings::new(DEFAULT_SCALE_BITS),
+        }
+    }
+
+    /// Panics unless `scale_bits` is between 8 and 15.
+    pub fn with_scale_bits(mut self, scale_bits: u32) -> Self {
+        self.settings = self.settings.with_scale_bits(scale_bits);
+        self
+    }
+
+    /// Panics unless `states` is 1, 2, 4 or 8.
+    pub fn with_states(mut self, states: usize) -> Self {
+        self.settings = self.settings.with_states(states);
+        self
+    }
+}
+
+impl Default for ANSOrder1Coder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn build_tables(data: &[u8], scale_bits: u32) -> Vec<Option<FrequencyTable>> {
+    let mut counts = vec![[0u64; 256]; 256];
+    let mut previous = 0u8;
+
+    // Count frequencies per context
+    for &byte in data {
+        counts[previous as usize][byte as usize] += 1;
+        previous = byte;
+    }
+
+    counts
+        .iter()
+        .map(|counts| {
+            counts
+                .iter()
+                .any(|&count| count > 0)
+                .then(|| FrequencyTable::new(normalize_frequencies(counts, scale_bits), scale_bits))
+        })
+        .collect()
+}
+
+fn write_tables(
+    tables: &[Option<FrequencyTable>],
+    scale_bits: u32,
+    output: &mut Vec<u8>,
+) -> io::Result<()> {
+    output.push(scale_bits as u8);
+
+    let mut bitmap = [0u8; 32];
+    for (context, table) in tables.iter().enumerate() {
+        if table.is_some() {
+            bitmap[context / 8] |= 1 << (context % 8);
+        }
+    }
+
+    output.extend_from_slice(&bitmap);
+
+    for table in tables.iter().flatten() {
+        let symbols: Vec<usize> = (0..256).filter(|&symbol| table.freq[symbol] > 0).collect();
+        varint::write(output, symbols.len() as u64 - 1)?;
+
+        let mut next = 0;
+        for symbol in symbols {
+            varint::write(output, (symbol - next) as u64)?;
+            varint::write(output, table.freq[symbol] as u64)?;
+            next = symbol + 1;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_tables<R: Read>(input: &mut R) -> io::Result<Vec<Option<FrequencyTable>>> {
+    let mut scale_bits = [0u8; 1];
+    input.read_exact(&mut scale_bits)?;
+    let scale_bits = scale_bits[0] as u32;
+
+    if !(8..=MAX_SCALE_BITS).contains(&scale_bits) {
+        return Err(malformed("Invalid frequency scale"));
+    }
+
+    let mut bitmap = [0u8; 32];
+    input.read_exact(&mut bitmap)?;
+
+    let mut tables = Vec::with_capacity(256);
+
+    for context in 0..256 {
+        if bitmap[context / 8] & (1 << (context % 8)) == 0 {
+            tables.push(None);
+            continue;
+        }
+
+        let count = varint::read(input)? + 1;
+
+        if count > 256 {
+            return Err(malformed("Invalid frequency table"));
+        }
+
+        let mut freq = [0u32; 256];
+        let mut total = 0u64;
+        let mut next = 0u64;
+
+        for _ in 0..count {
+            let symbol = next + varint::read(input)?;
+            let value = varint::read(input)?;
+            total += value;
+
+            if symbol > 255 || value == 0 || total > 1 << scale_bits {
+                return Err(malformed("Invalid frequency table"));
+            }
+
+            freq[symbol as usize] = value as u32;
+            next = symbol + 1;
+        }
+
+        if total != 1 << scale_bits {
+            return Err(malformed("Invalid frequency table"));
+        }
+
+        tables.push(Some(FrequencyTable::new(freq, scale_bits)));
+    }
+
+    Ok(tables)
+}
+
+impl Codec for ANSOrder1Coder {
+    fn name(&self) -> &'static str {
+        "ans-o1"
+    }
+
+    fn id(&self) -> u8 {
+        8
+    }
+
+    fn encode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+
+        // Write original length
+        varint::write_len(&mut output, bytes.len())?;
+
+        if bytes.is_empty() {
+            return Ok(output);
+        }
+
+        let tables = build_tables(bytes, self.settings.scale_bits);
+        write_tables(&tables, self.settings.scale_bits, &mut output)?;
+
+        // Every byte is coded with the table of its context
+        encode_interleaved(bytes, self.settings.states, &mut output, |context| {
+            tables[context as usize].as_ref().unwrap()
+        });
+
+        Ok(output)
+    }
+
+    fn decode(&self, bytes: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        let mut input = bytes;
+
+        // Read original length
+        let original_len = varint::read_len_at_most(&mut input, max_len)?;
+
+        if original_len == 0 {
+            return Ok(Vec::new());
+        }
+
+        // Read context tables
+        let tables = read_tables(&mut input)?;
+
+        decode_interleaved(input, original_len, |context| {
+            tables[context as usize].as_ref()
+        })
+    }
+}
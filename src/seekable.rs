@@ -0,0 +1,194 @@
+//! Random access to the uncompressed data of a [`container`] stream.
+//!
+//! The block index at the end of the stream gives the compressed offset and
+//! the uncompressed length of every block, so a read only decodes the blocks
+//! it touches.
+//!
+//! [`container`]: crate::container
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::{
+    checksum::Checksum,
+    codec::Codec,
+    container::{
+        self, BLOCK_SIZE, BlockEntry, HEADER_SIZE, Header, INDEX_ENTRY_SIZE, UNKNOWN_SIZE,
+    },
+    error::{CorruptionError, malformed},
+};
+
+// Block count and magic that end the index
+const INDEX_TRAILER_SIZE: u64 = 8;
+
+/// Decompresses any part of a stream read from `R`.
+///
+/// Every block that is read is checked against its own checksum; the
+/// checksum of the whole content is not, as it needs all of the data.
+pub struct SeekableDecoder<R: Read + Seek> {
+    reader: R,
+    // Position of the stream header in `R`
+    base: u64,
+    codec: Box<dyn Codec>,
+    checksum: Checksum,
+    index: Vec<BlockEntry>,
+    // Uncompressed offset of every block, and the total length at the end
+    starts: Vec<u64>,
+    position: u64,
+    // The block last decoded, by number
+    cached: Option<(usize, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> SeekableDecoder<R> {
+    /// Reads the header at the current position of `reader` and the index at
+    /// its end. Fails for streams written without an index.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let base = reader.stream_position()?;
+        let header = Header::read(&mut reader)?;
+        let codec = header.codec()?;
+        let checksum = header.checksum()?;
+
+        if !header.has_index() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Stream has no block index",
+            ));
+        }
+
+        let end = reader.seek(SeekFrom::End(0))?;
+
+        if end < base + HEADER_SIZE + INDEX_TRAILER_SIZE {
+            return Err(malformed("truncated input"));
+        }
+
+        reader.seek(SeekFrom::End(-(INDEX_TRAILER_SIZE as i64)))?;
+
+        let mut count = [0u8; 4];
+        reader.read_exact(&mut count)?;
+        let count = u32::from_be_bytes(count) as u64;
+
+        let index_size = count * INDEX_ENTRY_SIZE as u64 + INDEX_TRAILER_SIZE;
+
+        if index_size > end - base - HEADER_SIZE {
+            return Err(malformed("invalid block index"));
+        }
+
+        reader.seek(SeekFrom::Start(end - index_size))?;
+        let index = container::read_index(&mut reader, count as usize)?;
+
+        let mut starts = Vec::with_capacity(index.len() + 1);
+        let mut start = 0;
+        let mut offset = HEADER_SIZE;
+
+        for entry in &index {
+            if entry.offset != offset
+                || entry.original_size == 0
+                || entry.original_size as usize > BLOCK_SIZE
+            {
+                return Err(malformed("invalid block index"));
+            }
+
+            starts.push(start);
+            start += entry.original_size as u64;
+            offset += entry.size as u64;
+        }
+
+        starts.push(start);
+
+        if header.original_size != UNKNOWN_SIZE && header.original_size != start {
+            return Err(CorruptionError::SizeMismatch {
+                expected: header.original_size,
+                actual: start,
+            }
+            .into());
+        }
+
+        Ok(SeekableDecoder {
+            reader,
+            base,
+            codec,
+            checksum,
+            index,
+            starts,
+            position: 0,
+            cached: None,
+        })
+    }
+
+    /// Length of the uncompressed data.
+    pub fn len(&self) -> u64 {
+        self.starts[self.index.len()]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Returns block number `block`, decoding it unless it is cached.
+    fn block(&mut self, block: usize) -> io::Result<&[u8]> {
+        if self
+            .cached
+            .as_ref()
+            .is_none_or(|&(cached, _)| cached != block)
+        {
+            let entry = self.index[block];
+
+            self.reader
+                .seek(SeekFrom::Start(self.base + entry.offset))?;
+
+            let frame = container::read_frame(&mut self.reader, self.checksum)?
+                .filter(|frame| {
+                    frame.len() == entry.size as u64
+                        && frame.original_size() == entry.original_size as usize
+                })
+                .ok_or_else(|| malformed("block index does not match the blocks"))?;
+
+            let data = container::decode_frame(&frame, self.codec.as_ref(), self.checksum, block)?;
+            self.cached = Some((block, data));
+        }
+
+        Ok(&self.cached.as_ref().unwrap().1)
+    }
+}
+
+impl<R: Read + Seek> Read for SeekableDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len() || buf.is_empty() {
+            return Ok(0);
+        }
+
+        // The last block whose start is at or before the position
+        let block = self.starts.partition_point(|&start| start <= self.position) - 1;
+        let skip = (self.position - self.starts[block]) as usize;
+
+        let data = &self.block(block)?[skip..];
+        let len = buf.len().min(data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for SeekableDecoder<R> {
+    /// Seeking past the end is allowed; reads there return nothing.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta) => self.len().checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )
+        })?;
+
+        Ok(self.position)
+    }
+}
@@ -0,0 +1,368 @@
+//! Streaming adapters that read and write the [`container`] format block by
+//! block, so only one block has to be held in memory at a time.
+//!
+//! [`container`]: crate::container
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    mem, panic, thread,
+};
+
+use crate::{
+    checksum::{Checksum, Hasher},
+    codec::Codec,
+    container::{self, BLOCK_SIZE, BlockEntry, HEADER_SIZE, Header, UNKNOWN_SIZE},
+    error::{CorruptionError, malformed},
+};
+
+/// Runs `task` on every item, each on its own thread unless there is only
+/// one, and returns the results in order.
+fn run_parallel<T, U, F>(items: &[T], task: F) -> Vec<U>
+where
+    T: Sync,
+    U: Send,
+    F: Fn(&T) -> U + Sync,
+{
+    if items.len() <= 1 {
+        return items.iter().map(task).collect();
+    }
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = items
+            .iter()
+            .map(|item| scope.spawn(|| task(item)))
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect()
+    })
+}
+
+/// Compresses everything written to it into `W`.
+///
+/// The stream is completed by [`Encoder::finish`]. Dropping the encoder
+/// finishes it as well, but ignores any errors.
+pub struct Encoder<W: Write> {
+    writer: Option<W>,
+    codec: Box<dyn Codec>,
+    checksum: Checksum,
+    hasher: Hasher,
+    // Written with the first block, so that a size hint can still go in it
+    header_written: bool,
+    size_hint: u64,
+    total: u64,
+    buffer: Vec<u8>,
+    block_size: usize,
+    threads: usize,
+    // Full blocks waiting to be encoded together
+    pending: Vec<Vec<u8>>,
+    position: u64,
+    index: Vec<BlockEntry>,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(writer: W, codec: Box<dyn Codec>, checksum: Checksum) -> io::Result<Self> {
+        Ok(Encoder {
+            writer: Some(writer),
+            codec,
+            checksum,
+            hasher: checksum.hasher(),
+            header_written: false,
+            size_hint: UNKNOWN_SIZE,
+            total: 0,
+            buffer: Vec::new(),
+            block_size: BLOCK_SIZE,
+            threads: 1,
+            pending: Vec::new(),
+            position: HEADER_SIZE,
+            index: Vec::new(),
+        })
+    }
+
+    /// Cuts the input into blocks of `block_size` bytes. Smaller blocks take
+    /// less memory to encode and decode, and usually compress worse.
+    ///
+    /// Panics unless `block_size` is between 1 and [`BLOCK_SIZE`].
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        assert!((1..=BLOCK_SIZE).contains(&block_size));
+        self.block_size = block_size;
+        self
+    }
+
+    /// Encodes up to `threads` blocks at a time, each on its own thread. The
+    /// output is the same for any number of threads.
+    ///
+    /// Panics if `threads` is 0.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        assert!(threads > 0);
+        self.threads = threads;
+        self
+    }
+
+    /// Records `size` as the length of the input in the header, which the
+    /// decoder checks against the data it decodes. [`Encoder::finish`] fails
+    /// if a different amount was written.
+    pub fn with_size_hint(mut self, size: u64) -> Self {
+        self.size_hint = size;
+        self
+    }
+
+    /// Writes the pending block and the end of the stream, and returns the
+    /// underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.try_finish()?;
+        Ok(self.writer.take().unwrap())
+    }
+
+    fn try_finish(&mut self) -> io::Result<()> {
+        self.flush_block()?;
+        self.write_pending()?;
+
+        if self.size_hint != UNKNOWN_SIZE && self.size_hint != self.total {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes written, {} announced in the size hint",
+                    self.total, self.size_hint
+                ),
+            ));
+        }
+
+        let writer = self.writer.as_mut().unwrap();
+        container::write_end(writer, self.hasher.finish())?;
+        container::write_index(writer, &self.index)?;
+        writer.flush()
+    }
+
+    /// Queues the buffered data as a block, and encodes the queue once there
+    /// is a block for every thread.
+    fn flush_block(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        self.hasher.update(&self.buffer);
+        self.total += self.buffer.len() as u64;
+        self.pending.push(mem::take(&mut self.buffer));
+
+        if self.pending.len() == self.threads {
+            self.write_pending()?;
+        }
+
+        Ok(())
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        let codec = self.codec.as_ref();
+        let checksum = self.checksum;
+
+        if !self.header_written {
+            Header::new(codec, checksum, self.size_hint).write(self.writer.as_mut().unwrap())?;
+            self.header_written = true;
+        }
+
+        let encoded = run_parallel(&self.pending, |block| {
+            container::encode_block(codec, checksum, block)
+        });
+
+        let writer = self.writer.as_mut().unwrap();
+
+        for (block, encoded) in self.pending.drain(..).zip(encoded) {
+            let encoded = encoded?;
+            writer.write_all(&encoded)?;
+
+            self.index
+                .push(BlockEntry::new(self.position, &encoded, block.len()));
+            self.position += encoded.len() as u64;
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.block_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+
+        if self.buffer.len() == self.block_size {
+            self.flush_block()?;
+        }
+
+        Ok(len)
+    }
+
+    /// Encodes the data written so far as a (possibly short) block, so that a
+    /// reader can decode everything up to this point.
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_block()?;
+        self.write_pending()?;
+        self.writer.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for Encoder<W> {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+/// Decompresses a stream read from `R`.
+pub struct Decoder<R: Read> {
+    reader: R,
+    header: Header,
+    codec: Box<dyn Codec>,
+    checksum: Checksum,
+    hasher: Hasher,
+    threads: usize,
+    // Decoded blocks not handed out yet
+    decoded: VecDeque<Vec<u8>>,
+    block: Vec<u8>,
+    position: usize,
+    offset: u64,
+    index: Vec<BlockEntry>,
+    total: u64,
+    end_reached: bool,
+    finished: bool,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Reads the stream header; the algorithm is detected from it.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let header = Header::read(&mut reader)?;
+        let codec = header.codec()?;
+        let checksum = header.checksum()?;
+
+        Ok(Decoder {
+            reader,
+            header,
+            codec,
+            checksum,
+            hasher: checksum.hasher(),
+            threads: 1,
+            decoded: VecDeque::new(),
+            block: Vec::new(),
+            position: 0,
+            offset: HEADER_SIZE,
+            index: Vec::new(),
+            total: 0,
+            end_reached: false,
+            finished: false,
+        })
+    }
+
+    /// Reads up to `threads` blocks ahead and decodes them in parallel.
+    ///
+    /// Panics if `threads` is 0.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        assert!(threads > 0);
+        self.threads = threads;
+        self
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Reads the next blocks, as many as there are threads, and decodes them.
+    fn decode_blocks(&mut self) -> io::Result<()> {
+        let mut frames = Vec::with_capacity(self.threads);
+
+        while frames.len() < self.threads {
+            let Some(frame) = container::read_frame(&mut self.reader, self.checksum)? else {
+                self.end_reached = true;
+                break;
+            };
+
+            frames.push(frame);
+        }
+
+        let codec = self.codec.as_ref();
+        let checksum = self.checksum;
+        let first = self.index.len();
+
+        let numbered: Vec<_> = frames.iter().enumerate().collect();
+        let blocks = run_parallel(&numbered, |&(i, frame)| {
+            container::decode_frame(frame, codec, checksum, first + i)
+        });
+
+        for (frame, block) in frames.iter().zip(blocks) {
+            self.decoded.push_back(block?);
+
+            self.index.push(BlockEntry {
+                offset: self.offset,
+                size: frame.len() as u32,
+                original_size: frame.original_size() as u32,
+            });
+            self.offset += frame.len();
+        }
+
+        Ok(())
+    }
+
+    fn next_block(&mut self) -> io::Result<()> {
+        if self.decoded.is_empty() && !self.end_reached {
+            self.decode_blocks()?;
+        }
+
+        if let Some(block) = self.decoded.pop_front() {
+            self.hasher.update(&block);
+            self.total += block.len() as u64;
+            self.block = block;
+            self.position = 0;
+
+            return Ok(());
+        }
+
+        let expected = self.header.original_size;
+
+        if expected != UNKNOWN_SIZE && expected != self.total {
+            return Err(CorruptionError::SizeMismatch {
+                expected,
+                actual: self.total,
+            }
+            .into());
+        }
+
+        container::read_end(&mut self.reader, self.hasher.finish())?;
+
+        if self.header.has_index()
+            && container::read_index(&mut self.reader, self.index.len())? != self.index
+        {
+            return Err(malformed("block index does not match the blocks"));
+        }
+
+        self.block.clear();
+        self.position = 0;
+        self.finished = true;
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.block.len() {
+            if self.finished {
+                return Ok(0);
+            }
+
+            self.next_block()?;
+        }
+
+        let len = buf.len().min(self.block.len() - self.position);
+        buf[..len].copy_from_slice(&self.block[self.position..self.position + len]);
+        self.position += len;
+
+        Ok(len)
+    }
+}
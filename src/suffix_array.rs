@@ -0,0 +1,296 @@
+//! Linear-time suffix array construction by induced sorting (SA-IS).
+//!
+//! Based on Nong, Zhang & Chan, "Two Efficient Algorithms for Linear Time
+//! Suffix Array Construction". The text is treated as if it were followed by
+//! a virtual sentinel that is smaller than every symbol.
+
+const EMPTY: u32 = u32::MAX;
+
+trait Symbol: Copy + Ord {
+    fn index(self) -> usize;
+}
+
+impl Symbol for u8 {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl Symbol for u32 {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Returns the start positions of all suffixes of `text` in sorted order.
+///
+/// Panics if `text` is 4 GiB or longer.
+pub fn suffix_array(text: &[u8]) -> Vec<u32> {
+    assert!(
+        text.len() < EMPTY as usize,
+        "text too long for a suffix array"
+    );
+
+    let mut sa = vec![0; text.len()];
+    sais(text, 256, &mut sa);
+    sa
+}
+
+/// Suffix types, one bit per position, set for S-type suffixes.
+struct Types {
+    bits: Vec<u64>,
+}
+
+impl Types {
+    fn new(n: usize) -> Self {
+        Types {
+            bits: vec![0; n.div_ceil(64)],
+        }
+    }
+
+    fn is_s(&self, i: usize) -> bool {
+        self.bits[i / 64] >> (i % 64) & 1 != 0
+    }
+
+    fn set_s(&mut self, i: usize) {
+        self.bits[i / 64] |= 1 << (i % 64);
+    }
+
+    /// Whether an S-type suffix starts at `i` right after an L-type one.
+    fn is_lms(&self, i: usize) -> bool {
+        i > 0 && self.is_s(i) && !self.is_s(i - 1)
+    }
+}
+
+fn sais<T: Symbol>(text: &[T], alphabet_size: usize, sa: &mut [u32]) {
+    let n = text.len();
+
+    match n {
+        0 => return,
+        1 => {
+            sa[0] = 0;
+            return;
+        }
+        _ => {}
+    }
+
+    // Classify suffixes: S-type if smaller than the following suffix. The
+    // last suffix is L-type because the sentinel after it is smaller.
+    let mut types = Types::new(n);
+    for i in (0..n - 1).rev() {
+        if text[i] < text[i + 1] || (text[i] == text[i + 1] && types.is_s(i + 1)) {
+            types.set_s(i);
+        }
+    }
+
+    let mut counts = vec![0u32; alphabet_size];
+    for &symbol in text {
+        counts[symbol.index()] += 1;
+    }
+
+    // Sort LMS substrings by inducing from LMS positions in arbitrary order
+    sa.fill(EMPTY);
+    let mut tails = bucket_tails(&counts);
+    for (i, symbol) in text.iter().enumerate().skip(1) {
+        if types.is_lms(i) {
+            let bucket = symbol.index();
+            tails[bucket] -= 1;
+            sa[tails[bucket] as usize] = i as u32;
+        }
+    }
+
+    induce(text, &types, &counts, sa);
+
+    // Move the sorted LMS positions to the front
+    let mut m = 0;
+    for i in 0..n {
+        let position = sa[i] as usize;
+        if types.is_lms(position) {
+            sa[m] = position as u32;
+            m += 1;
+        }
+    }
+
+    // Name LMS substrings; equal substrings get equal names. Names are
+    // stored at m + position / 2, which is unique because LMS positions are
+    // at least two apart.
+    sa[m..].fill(EMPTY);
+    let mut names = 0;
+    let mut previous = None;
+
+    for i in 0..m {
+        let position = sa[i] as usize;
+
+        let equal =
+            previous.is_some_and(|previous| lms_substrings_equal(text, &types, previous, position));
+
+        if !equal {
+            names += 1;
+        }
+
+        previous = Some(position);
+        sa[m + position / 2] = names - 1;
+    }
+
+    // Pack the names into the end of the array, in text order
+    let mut j = n;
+    for i in (m..n).rev() {
+        if sa[i] != EMPTY {
+            j -= 1;
+            sa[j] = sa[i];
+        }
+    }
+
+    // Sort the LMS suffixes, recursing if the names are not yet unique
+    let (reduced_sa, reduced_text) = sa.split_at_mut(n - m);
+    let reduced_sa = &mut reduced_sa[..m];
+
+    if (names as usize) < m {
+        sais(&*reduced_text, names as usize, reduced_sa);
+    } else {
+        for (i, &name) in reduced_text.iter().enumerate() {
+            reduced_sa[name as usize] = i as u32;
+        }
+    }
+
+    // Translate reduced suffix indices back into text positions
+    let mut j = 0;
+    for i in 1..n {
+        if types.is_lms(i) {
+            reduced_text[j] = i as u32;
+            j += 1;
+        }
+    }
+
+    for entry in reduced_sa.iter_mut() {
+        *entry = reduced_text[*entry as usize];
+    }
+
+    // Induce the final order from the sorted LMS suffixes
+    sa[m..].fill(EMPTY);
+    let mut tails = bucket_tails(&counts);
+    for i in (0..m).rev() {
+        let position = sa[i] as usize;
+        sa[i] = EMPTY;
+
+        let bucket = text[position].index();
+        tails[bucket] -= 1;
+        sa[tails[bucket] as usize] = position as u32;
+    }
+
+    induce(text, &types, &counts, sa);
+}
+
+fn induce<T: Symbol>(text: &[T], types: &Types, counts: &[u32], sa: &mut [u32]) {
+    let n = text.len();
+
+    // L-type suffixes, starting with the one preceding the sentinel
+    let mut heads = bucket_heads(counts);
+    let bucket = text[n - 1].index();
+    sa[heads[bucket] as usize] = (n - 1) as u32;
+    heads[bucket] += 1;
+
+    for i in 0..n {
+        let position = sa[i];
+        if position != EMPTY && position > 0 && !types.is_s(position as usize - 1) {
+            let bucket = text[position as usize - 1].index();
+            sa[heads[bucket] as usize] = position - 1;
+            heads[bucket] += 1;
+        }
+    }
+
+    // S-type suffixes
+    let mut tails = bucket_tails(counts);
+
+    for i in (0..n).rev() {
+        let position = sa[i];
+        if position != EMPTY && position > 0 && types.is_s(position as usize - 1) {
+            let bucket = text[position as usize - 1].index();
+            tails[bucket] -= 1;
+            sa[tails[bucket] as usize] = position - 1;
+        }
+    }
+}
+
+fn lms_substrings_equal<T: Symbol>(text: &[T], types: &Types, a: usize, b: usize) -> bool {
+    let n = text.len();
+    for offset in 0.. {
+        let (i, j) = (a + offset, b + offset);
+
+        // Only one substring can end at the sentinel
+        if i == n || j == n {
+            return false;
+        }
+
+        if text[i] != text[j] || types.is_s(i) != types.is_s(j) {
+            return false;
+        }
+
+        if offset > 0 && (types.is_lms(i) || types.is_lms(j)) {
+            return types.is_lms(i) && types.is_lms(j);
+        }
+    }
+
+    unreachable!()
+}
+
+fn bucket_heads(counts: &[u32]) -> Vec<u32> {
+    let mut total = 0;
+
+    counts
+        .iter()
+        .map(|&count| {
+            let head = total;
+            total += count;
+            head
+        })
+        .collect()
+}
+
+fn bucket_tails(counts: &[u32]) -> Vec<u32> {
+    let mut total = 0;
+
+    counts
+        .iter()
+        .map(|&count| {
+            total += count;
+            total
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_sorts_suffixes(text: &[u8]) {
+        let mut expected: Vec<u32> = (0..text.len() as u32).collect();
+        expected.sort_by_key(|&start| &text[start as usize..]);
+
+        assert_eq!(suffix_array(text), expected, "{text:?}");
+    }
+
+    #[test]
+    fn matches_sorted_suffixes() {
+        let mut state = 1u64;
+
+        for len in 0..300 {
+            let alphabet = [2, 3, 4, 256][len % 4];
+            let text: Vec<u8> = (0..len)
+                .map(|_| {
+                    state = state
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    ((state >> 33) % alphabet) as u8
+                })
+                .collect();
+
+            assert_sorts_suffixes(&text);
+        }
+
+        // Repetitions make the recursion go deep
+        assert_sorts_suffixes(&[7; 200]);
+        assert_sorts_suffixes(&b"ab".repeat(100));
+        assert_sorts_suffixes(&b"abaab".repeat(40));
+    }
+}
@@ -0,0 +1,63 @@
+//! Unsigned LEB128 variable-length integers: seven bits per byte, least
+//! significant group first, high bit set on every byte but the last.
+//!
+//! Every codec that records a length writes it this way, so compressed data
+//! does not depend on the byte order or the width of `usize` of the machine
+//! that wrote it.
+
+use std::io::{self, Read, Write};
+
+use crate::error::malformed;
+
+pub fn write<W: Write>(writer: &mut W, mut value: u64) -> io::Result<()> {
+    while value >= 0x80 {
+        writer.write_all(&[value as u8 | 0x80])?;
+        value >>= 7;
+    }
+
+    writer.write_all(&[value as u8])
+}
+
+pub fn read<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut value = 0u64;
+
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+
+        if shift == 63 && byte[0] > 1 {
+            return Err(malformed("varint overflows 64 bits"));
+        }
+
+        value |= ((byte[0] & 0x7F) as u64) << shift;
+
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(malformed("varint too long"))
+}
+
+/// Writes a length with [`write`].
+pub fn write_len<W: Write>(writer: &mut W, len: usize) -> io::Result<()> {
+    write(writer, len as u64)
+}
+
+/// Reads a length written by [`write_len`], which may not fit in `usize` on
+/// the machine reading it.
+pub fn read_len<R: Read>(reader: &mut R) -> io::Result<usize> {
+    usize::try_from(read(reader)?).map_err(|_| malformed("length does not fit in usize"))
+}
+
+/// Reads a length with [`read_len`] and checks it against `max` before
+/// anything is allocated for it.
+pub fn read_len_at_most<R: Read>(reader: &mut R, max: usize) -> io::Result<usize> {
+    let len = read_len(reader)?;
+
+    if len > max {
+        return Err(malformed("length larger than the block"));
+    }
+
+    Ok(len)
+}
@@ -0,0 +1,68 @@
+//! The container passes every codec the size it recorded for the block, and
+//! no codec may decode past it, whatever length its own data claims. A few
+//! bytes can claim gigabytes, so these must fail before decoding them.
+
+mod common;
+
+use std::io::{self, ErrorKind};
+
+use common::data;
+use markov_huffman::codec;
+
+// Codecs whose data starts with a LEB128 length, of the input or of their
+// BWT output
+const LENGTH_FIRST: &[&str] = &[
+    "markov-huffman",
+    "markov-arithmetic",
+    "bwt-mtf-rle-huffman",
+    "bwt-multi-huffman",
+    "ans",
+    "ans-lib",
+    "ans-o1",
+];
+
+fn assert_malformed(result: io::Result<Vec<u8>>, name: &str) {
+    match result {
+        Ok(output) => panic!("{name} decoded {} bytes", output.len()),
+        Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidData, "{name}: {e}"),
+    }
+}
+
+#[test]
+fn output_longer_than_the_block_is_rejected() {
+    let input = data("input.txt");
+
+    for codec in codec::codecs() {
+        let encoded = codec.encode(&input).unwrap();
+
+        assert_malformed(codec.decode(&encoded, input.len() - 1), codec.name());
+        assert_malformed(codec.decode(&encoded, 0), codec.name());
+    }
+}
+
+#[test]
+fn huge_lengths_are_rejected() {
+    // LEB128 for 2^31, in place of the one-byte length of a short input
+    let huge = [0x80, 0x80, 0x80, 0x80, 0x08];
+    let input = b"abracadabra";
+
+    for &name in LENGTH_FIRST {
+        let codec = codec::by_name(name).unwrap();
+        let encoded = codec.encode(input).unwrap();
+        assert!(encoded[0] < 0x80, "{name}");
+
+        let bomb = [&huge[..], &encoded[1..]].concat();
+        assert_malformed(codec.decode(&bomb, 1 << 23), name);
+    }
+}
+
+#[test]
+fn zero_runs_stop_at_the_block() {
+    let codec = codec::by_name("bwt").unwrap();
+
+    // A chunk header for zero runs, then RUNB digits for a run of 2^23 - 2
+    let mut bomb = (1u32 << 24).to_be_bytes().to_vec();
+    bomb.extend([1; 22]);
+
+    assert_malformed(codec.decode(&bomb, 100), "bwt");
+}
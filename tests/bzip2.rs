@@ -0,0 +1,99 @@
+//! The `.bz2` streams in `tests/data` were written by bzip2 1.0.8 from
+//! `input.txt` at `-9`, from three copies of it at `-1`, which takes two
+//! blocks, and from [`runs`] at `-9`. Where the `bzip2` tool is installed,
+//! streams also go through it in both directions.
+
+mod common;
+
+use std::{
+    io::{self, Write},
+    process::{Command, Stdio},
+};
+
+use common::data;
+use markov_huffman::bzip2;
+
+fn repeated() -> Vec<u8> {
+    data("input.txt").repeat(3)
+}
+
+/// Runs of every length from 1 to 600, which exercise both the run-length
+/// coding of the input and the zero runs after the BWT.
+fn runs() -> Vec<u8> {
+    (1..=600)
+        .flat_map(|length| std::iter::repeat_n((length * 7 % 251) as u8, length))
+        .collect()
+}
+
+/// Runs `bzip2` with `args` on `input`, or returns `None` if it is not
+/// installed.
+fn run_bzip2(args: &[&str], input: &[u8]) -> Option<Vec<u8>> {
+    let mut child = match Command::new("bzip2")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => panic!("bzip2: {e}"),
+    };
+
+    // Write from another thread so that neither side blocks on a full pipe
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+
+    let output = child.wait_with_output().unwrap();
+    writer.join().unwrap().unwrap();
+    assert!(output.status.success(), "bzip2 {args:?} failed");
+
+    Some(output.stdout)
+}
+
+#[test]
+fn stored_streams_decode() {
+    assert!(bzip2::decompress(&data("input.bz2")).unwrap() == data("input.txt"));
+    assert!(bzip2::decompress(&data("input-repeated-level-1.bz2")).unwrap() == repeated());
+    assert!(bzip2::decompress(&data("runs.bz2")).unwrap() == runs());
+}
+
+#[test]
+fn concatenated_streams_decode() {
+    let compressed = [data("input.bz2"), data("runs.bz2")].concat();
+    let expected = [data("input.txt"), runs()].concat();
+
+    assert!(bzip2::decompress(&compressed).unwrap() == expected);
+}
+
+#[test]
+fn round_trip() {
+    for input in [Vec::new(), data("input.txt"), repeated(), runs()] {
+        for level in [1, 9] {
+            let compressed = bzip2::compress(&input, level).unwrap();
+            assert!(bzip2::decompress(&compressed).unwrap() == input);
+        }
+    }
+}
+
+#[test]
+fn round_trip_through_bzip2() {
+    for input in [Vec::new(), data("input.txt"), repeated(), runs()] {
+        for level in [1, 9] {
+            let compressed = bzip2::compress(&input, level).unwrap();
+
+            let Some(decompressed) = run_bzip2(&["-d", "-c"], &compressed) else {
+                eprintln!("bzip2 not found, skipping");
+                return;
+            };
+
+            assert!(
+                decompressed == input,
+                "bzip2 decodes level {level} differently"
+            );
+
+            let compressed = run_bzip2(&[&format!("-{level}"), "-c"], &input).unwrap();
+            assert!(bzip2::decompress(&compressed).unwrap() == input);
+        }
+    }
+}
@@ -0,0 +1,12 @@
+//! Helpers shared by the integration tests.
+
+use std::{fs, path::PathBuf};
+
+/// Reads `name` from `tests/data`.
+pub fn data(name: &str) -> Vec<u8> {
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "data", name]
+        .iter()
+        .collect();
+
+    fs::read(&path).unwrap_or_else(|e| panic!("{}: {e}", path.display()))
+}
@@ -0,0 +1,88 @@
+//! Compressed data must not depend on the machine that wrote it: lengths are
+//! LEB128 and fixed-width fields have a fixed byte order, so nothing follows
+//! the width of `usize` or the native endianness.
+//!
+//! The streams in `tests/data` were written on a 64-bit little-endian target.
+//! Decoding them and re-encoding their input byte for byte on any other
+//! target, such as a 32-bit one, shows that both sides agree on the format.
+
+mod common;
+
+use std::io::Write;
+
+use common::data;
+use markov_huffman::{
+    checksum::Checksum,
+    codec::{self, CompressionOptions},
+    container,
+    stream::Encoder,
+};
+
+// Every codec, with a stream of `input.txt` each
+const CODECS: &[&str] = &[
+    "markov-huffman",
+    "markov-arithmetic",
+    "bwt",
+    "bwt-huffman",
+    "bwt-mtf-rle-huffman",
+    "bwt-multi-huffman",
+    "ans",
+    "ans-lib",
+    "ans-o1",
+    "adaptive-huffman",
+    "adaptive-huffman-o1",
+];
+
+// Written at level 1, whose blocks are smaller than the input
+const LEVEL_1_CODEC: &str = "bwt-multi-huffman";
+
+fn compress_level_1(input: &[u8]) -> Vec<u8> {
+    let options = CompressionOptions::from_level(1);
+    let codec = codec::by_name_with_options(LEVEL_1_CODEC, &options).unwrap();
+
+    let mut encoder = Encoder::new(Vec::new(), codec, Checksum::Crc32)
+        .unwrap()
+        .with_block_size(options.block_size());
+
+    encoder.write_all(input).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[test]
+fn stored_streams_decode() {
+    let input = data("input.txt");
+
+    for name in CODECS {
+        let compressed = data(&format!("{name}.mh"));
+        let decompressed = container::decompress(&compressed).unwrap();
+        assert!(decompressed == input, "{name} decodes to different data");
+    }
+
+    let compressed = data(&format!("{LEVEL_1_CODEC}-level-1.mh"));
+    assert!(container::decompress(&compressed).unwrap() == input);
+}
+
+#[test]
+fn encoding_matches_stored_streams() {
+    let input = data("input.txt");
+
+    for name in CODECS {
+        let codec = codec::by_name(name).unwrap();
+        let compressed = container::compress(codec.as_ref(), &input, Checksum::Crc32).unwrap();
+        assert!(
+            compressed == data(&format!("{name}.mh")),
+            "{name} encodes differently"
+        );
+    }
+
+    let compressed = compress_level_1(&input);
+    assert!(compressed == data(&format!("{LEVEL_1_CODEC}-level-1.mh")));
+}
+
+#[test]
+fn every_codec_has_a_stored_stream() {
+    for codec in codec::codecs() {
+        let name = codec.name();
+        assert!(CODECS.contains(&name), "no stored stream for {name}");
+    }
+}
@@ -0,0 +1,102 @@
+//! Corrupted streams fail with a [`CorruptionError`], whether a checksum
+//! catches them or the codec does.
+
+mod common;
+
+use common::data;
+use markov_huffman::{checksum::Checksum, codec, container, error::CorruptionError};
+
+// Start of the payload of the first block: magic, version, algorithm, flags
+// and original size, then the original and compressed size of the block
+const PAYLOAD: usize = 15 + 8;
+
+fn corruption(compressed: &[u8]) -> &'static str {
+    let error = container::decompress(compressed).unwrap_err();
+
+    match CorruptionError::find(&error) {
+        Some(CorruptionError::BlockChecksum { .. }) => "block checksum",
+        Some(CorruptionError::ContentChecksum { .. }) => "content checksum",
+        Some(CorruptionError::SizeMismatch { .. }) => "size mismatch",
+        Some(CorruptionError::Malformed(_)) => "malformed",
+        None => panic!("not a corruption error: {error}"),
+    }
+}
+
+#[test]
+fn flipped_payload_is_caught_by_the_block_checksum() {
+    let input = data("input.txt");
+    let codec = codec::by_name("bwt").unwrap();
+
+    for checksum in [Checksum::Crc32, Checksum::XxHash32] {
+        let mut compressed = container::compress(codec.as_ref(), &input, checksum).unwrap();
+
+        // A byte of a (length, byte) pair after the chunk header, which still
+        // decodes, to other data
+        compressed[PAYLOAD + 4 + 2 * 100 + 1] ^= 1;
+
+        assert_eq!(corruption(&compressed), "block checksum", "{checksum}");
+    }
+}
+
+#[test]
+fn flipped_trailer_is_caught_by_the_content_checksum() {
+    let input = data("input.txt");
+    let codec = codec::by_name("markov-huffman").unwrap();
+
+    for checksum in [Checksum::Crc32, Checksum::XxHash32] {
+        let mut compressed = container::compress(codec.as_ref(), &input, checksum).unwrap();
+
+        // The checksum comes before an index of one block
+        let trailer = compressed.len() - (16 + 4 + 4) - 4;
+        compressed[trailer] ^= 1;
+
+        assert_eq!(corruption(&compressed), "content checksum", "{checksum}");
+    }
+}
+
+#[test]
+fn longer_block_size_is_a_size_mismatch() {
+    let input = data("input.txt");
+    let codec = codec::by_name("markov-huffman").unwrap();
+    let mut compressed = container::compress(codec.as_ref(), &input, Checksum::Crc32).unwrap();
+
+    // One more byte in the original size of the block than the payload holds
+    let size = u32::from_be_bytes(compressed[15..19].try_into().unwrap());
+    compressed[15..19].copy_from_slice(&(size + 1).to_be_bytes());
+
+    assert_eq!(corruption(&compressed), "size mismatch");
+}
+
+#[test]
+fn corrupted_payloads_without_checksums_are_corruption_errors() {
+    let input = &data("input.txt")[..4000];
+
+    for codec in codec::codecs() {
+        let compressed = container::compress(codec.as_ref(), input, Checksum::None).unwrap();
+        let payload = u32::from_be_bytes(compressed[19..23].try_into().unwrap()) as usize;
+
+        for flip in 0..50 {
+            let mut corrupted = compressed.clone();
+            corrupted[PAYLOAD + flip * 7919 % payload] ^= 1 << (flip % 8);
+
+            if let Err(error) = container::decompress(&corrupted) {
+                assert!(
+                    CorruptionError::find(&error).is_some(),
+                    "{}: {error}",
+                    codec.name()
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn crc32_known_answer() {
+    assert_eq!(Checksum::Crc32.compute(b"123456789"), Some(0xcbf43926));
+}
+
+#[test]
+fn xxhash32_known_answers() {
+    assert_eq!(Checksum::XxHash32.compute(b""), Some(0x02cc5d05));
+    assert_eq!(Checksum::XxHash32.compute(b"abc"), Some(0x32d153ff));
+}
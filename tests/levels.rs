@@ -0,0 +1,82 @@
+//! Every codec encodes at every level, and the levels change what they say
+//! they change: the block size, and the model order where a codec has both.
+
+mod common;
+
+use std::io::{Read, Write};
+
+use common::data;
+use markov_huffman::{
+    checksum::Checksum,
+    codec::{self, Codec, CompressionOptions},
+    container::INDEX_MAGIC,
+    huffman::HuffmanCoder,
+    stream::{Decoder, Encoder},
+};
+
+fn compress(name: &str, input: &[u8], level: u32) -> Vec<u8> {
+    let options = CompressionOptions::from_level(level);
+    let codec = codec::by_name_with_options(name, &options).unwrap();
+
+    let mut encoder = Encoder::new(Vec::new(), codec, Checksum::Crc32)
+        .unwrap()
+        .with_block_size(options.block_size());
+    encoder.write_all(input).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn block_count(compressed: &[u8]) -> u32 {
+    let (rest, magic) = compressed.split_at(compressed.len() - 4);
+    assert_eq!(magic, INDEX_MAGIC);
+
+    u32::from_be_bytes(rest[rest.len() - 4..].try_into().unwrap())
+}
+
+#[test]
+fn every_codec_round_trips_at_every_level() {
+    let input = data("input.txt");
+
+    for codec in codec::codecs() {
+        for level in [1, 5, 9] {
+            let compressed = compress(codec.name(), &input, level);
+            let mut decompressed = Vec::new();
+            Decoder::new(&compressed[..])
+                .unwrap()
+                .read_to_end(&mut decompressed)
+                .unwrap();
+
+            assert!(decompressed == input, "{} at level {level}", codec.name());
+        }
+    }
+}
+
+#[test]
+fn level_1_uses_small_blocks() {
+    let input = data("input.txt");
+    let options = CompressionOptions::from_level(1);
+
+    assert_eq!(options.block_size(), 32 * 1024);
+    assert_eq!(block_count(&compress("markov-huffman", &input, 1)), 2);
+    assert_eq!(block_count(&compress("markov-huffman", &input, 9)), 1);
+}
+
+#[test]
+fn level_1_uses_order0_huffman() {
+    let input = data("input.txt");
+    let level = CompressionOptions::from_level(1);
+    assert!(!level.order1());
+
+    let encode = |options: &CompressionOptions| {
+        codec::by_name_with_options("markov-huffman", options)
+            .unwrap()
+            .encode(&input)
+            .unwrap()
+    };
+    let order0 = HuffmanCoder::new()
+        .with_options(&CompressionOptions::new().with_order1(false))
+        .encode(&input)
+        .unwrap();
+
+    assert_eq!(encode(&level), order0);
+    assert_ne!(encode(&CompressionOptions::new()), order0);
+}
@@ -0,0 +1,122 @@
+//! Reads through [`SeekableDecoder`] must match the same slice of the input
+//! wherever they start and end: on either side of a block boundary, relative
+//! to the end, and past it.
+
+mod common;
+
+use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+
+use common::data;
+use markov_huffman::{checksum::Checksum, codec, seekable::SeekableDecoder, stream::Encoder};
+
+const BLOCK_SIZE: usize = 4096;
+
+fn decoder(input: &[u8]) -> SeekableDecoder<Cursor<Vec<u8>>> {
+    let codec = codec::by_name("markov-huffman").unwrap();
+    let mut encoder = Encoder::new(Vec::new(), codec, Checksum::Crc32)
+        .unwrap()
+        .with_block_size(BLOCK_SIZE);
+
+    encoder.write_all(input).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    SeekableDecoder::new(Cursor::new(compressed)).unwrap()
+}
+
+/// Reads up to `len` bytes from the current position.
+fn read(decoder: &mut SeekableDecoder<Cursor<Vec<u8>>>, len: usize) -> Vec<u8> {
+    let mut output = Vec::new();
+    decoder.take(len as u64).read_to_end(&mut output).unwrap();
+    output
+}
+
+#[test]
+fn reads_around_block_boundaries() {
+    let input = data("input.txt");
+    let mut decoder = decoder(&input);
+
+    assert_eq!(decoder.len(), input.len() as u64);
+
+    for boundary in (0..=input.len()).step_by(BLOCK_SIZE) {
+        for start in boundary.saturating_sub(2)..(boundary + 2).min(input.len()) {
+            for len in [1, 2, 3, BLOCK_SIZE, BLOCK_SIZE + 5] {
+                let end = (start + len).min(input.len());
+
+                assert_eq!(
+                    decoder.seek(SeekFrom::Start(start as u64)).unwrap(),
+                    start as u64
+                );
+                assert!(
+                    read(&mut decoder, len) == input[start..end],
+                    "{start}+{len}"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn seeks_from_the_end() {
+    let input = data("input.txt");
+    let mut decoder = decoder(&input);
+    let len = input.len();
+
+    for back in [1, 100, BLOCK_SIZE, BLOCK_SIZE + 1, len] {
+        let position = decoder.seek(SeekFrom::End(-(back as i64))).unwrap();
+
+        assert_eq!(position, (len - back) as u64);
+        assert!(read(&mut decoder, back) == input[len - back..]);
+    }
+
+    assert_eq!(decoder.seek(SeekFrom::End(0)).unwrap(), len as u64);
+    assert!(read(&mut decoder, 10).is_empty());
+
+    let error = decoder.seek(SeekFrom::End(-(len as i64) - 1)).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn seeks_relative_to_the_position() {
+    let input = data("input.txt");
+    let mut decoder = decoder(&input);
+
+    decoder
+        .seek(SeekFrom::Start(BLOCK_SIZE as u64 - 10))
+        .unwrap();
+    assert!(read(&mut decoder, 20) == input[BLOCK_SIZE - 10..BLOCK_SIZE + 10]);
+
+    let position = decoder.seek(SeekFrom::Current(-20)).unwrap();
+    assert_eq!(position, BLOCK_SIZE as u64 - 10);
+    assert!(read(&mut decoder, 20) == input[BLOCK_SIZE - 10..BLOCK_SIZE + 10]);
+
+    let error = decoder
+        .seek(SeekFrom::Current(-(BLOCK_SIZE as i64) - 11))
+        .unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn reads_past_the_end_are_empty() {
+    let input = data("input.txt");
+    let mut decoder = decoder(&input);
+    let len = input.len() as u64;
+
+    for position in [len, len + 1, len + BLOCK_SIZE as u64, u64::MAX] {
+        assert_eq!(decoder.seek(SeekFrom::Start(position)).unwrap(), position);
+        assert!(read(&mut decoder, 10).is_empty());
+    }
+
+    // And the data is still there when seeking back
+    decoder.seek(SeekFrom::Start(len + 5)).unwrap();
+    decoder.seek(SeekFrom::Current(-10)).unwrap();
+    assert!(read(&mut decoder, 10) == input[input.len() - 5..]);
+}
+
+#[test]
+fn empty_stream() {
+    let mut decoder = decoder(&[]);
+
+    assert!(decoder.is_empty());
+    assert!(read(&mut decoder, 10).is_empty());
+    assert_eq!(decoder.seek(SeekFrom::End(0)).unwrap(), 0);
+}
@@ -0,0 +1,74 @@
+//! A stream encoder told the size of its input records it in the header like
+//! the one-shot compressor does, and the decoder holds the data to it.
+
+mod common;
+
+use std::io::{Read, Write};
+
+use common::data;
+use markov_huffman::{
+    checksum::Checksum,
+    codec, container,
+    error::CorruptionError,
+    stream::{Decoder, Encoder},
+};
+
+fn compress(input: &[u8], size_hint: u64) -> Vec<u8> {
+    let codec = codec::by_name("markov-huffman").unwrap();
+    let mut encoder = Encoder::new(Vec::new(), codec, Checksum::Crc32)
+        .unwrap()
+        .with_size_hint(size_hint);
+
+    encoder.write_all(input).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[test]
+fn hinted_stream_matches_one_shot_compression() {
+    let input = data("input.txt");
+    let codec = codec::by_name("markov-huffman").unwrap();
+
+    let compressed = compress(&input, input.len() as u64);
+    assert_eq!(
+        compressed,
+        container::compress(codec.as_ref(), &input, Checksum::Crc32).unwrap()
+    );
+
+    let mut decoder = Decoder::new(&compressed[..]).unwrap();
+    assert_eq!(decoder.header().original_size, input.len() as u64);
+
+    let mut decompressed = Vec::new();
+    decoder.read_to_end(&mut decompressed).unwrap();
+    assert_eq!(decompressed, input);
+}
+
+#[test]
+fn finish_fails_when_the_hint_is_wrong() {
+    let input = data("input.txt");
+
+    for size_hint in [0, input.len() as u64 - 1, input.len() as u64 + 1] {
+        let codec = codec::by_name("markov-huffman").unwrap();
+        let mut encoder = Encoder::new(Vec::new(), codec, Checksum::Crc32)
+            .unwrap()
+            .with_size_hint(size_hint);
+
+        encoder.write_all(&input).unwrap();
+        assert!(encoder.finish().is_err(), "{size_hint}");
+    }
+}
+
+#[test]
+fn decoder_checks_the_recorded_size() {
+    let input = data("input.txt");
+    let mut compressed = compress(&input, input.len() as u64);
+
+    // The original size in the header, after magic, version, algorithm and
+    // flags
+    compressed[7..15].copy_from_slice(&(input.len() as u64 + 1).to_be_bytes());
+
+    let error = container::decompress(&compressed).unwrap_err();
+    assert!(matches!(
+        CorruptionError::find(&error),
+        Some(CorruptionError::SizeMismatch { .. })
+    ));
+}
@@ -0,0 +1,62 @@
+//! The stream encoder promises the same output for any number of threads,
+//! and the decoder reads it back with any number of threads. Small blocks
+//! give every thread count several rounds, including a partial last one.
+
+mod common;
+
+use std::io::{Read, Write};
+
+use common::data;
+use markov_huffman::{
+    checksum::Checksum,
+    codec,
+    stream::{Decoder, Encoder},
+};
+
+const BLOCK_SIZE: usize = 3000;
+const THREADS: &[usize] = &[2, 3, 4, 8];
+
+fn compress(name: &str, input: &[u8], threads: usize) -> Vec<u8> {
+    let mut encoder = Encoder::new(Vec::new(), codec::by_name(name).unwrap(), Checksum::Crc32)
+        .unwrap()
+        .with_block_size(BLOCK_SIZE)
+        .with_threads(threads);
+
+    // Writes that do not line up with the blocks
+    for part in input.chunks(1000) {
+        encoder.write_all(part).unwrap();
+    }
+
+    encoder.finish().unwrap()
+}
+
+#[test]
+fn output_does_not_depend_on_threads() {
+    let input = data("input.txt");
+
+    for codec in codec::codecs() {
+        let name = codec.name();
+        let expected = compress(name, &input, 1);
+
+        for &threads in THREADS {
+            assert!(
+                compress(name, &input, threads) == expected,
+                "{name} with {threads} threads"
+            );
+        }
+    }
+}
+
+#[test]
+fn decoding_does_not_depend_on_threads() {
+    let input = data("input.txt");
+    let compressed = compress("markov-huffman", &input, 1);
+
+    for &threads in [1].iter().chain(THREADS) {
+        let mut decoder = Decoder::new(&compressed[..]).unwrap().with_threads(threads);
+        let mut output = Vec::new();
+        decoder.read_to_end(&mut output).unwrap();
+
+        assert!(output == input, "{threads} threads");
+    }
+}